@@ -1,15 +1,34 @@
 use serde::{Deserialize, Serialize};
 
-use crate::functions::{http_request, load_gen, rhai_code, sleep};
+use crate::functions::{
+    exec, graphql, grpc, http_request, http_session, load_gen, redis, rendezvous, rhai_code, sleep,
+    socket, sql, websocket,
+};
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Flow {
     pub functions: Vec<Function>,
+
+    #[serde(default)]
+    pub http: Option<http_request::HttpConfig>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum Function {
-    HttpRequest(http_request::HttpRequestParam),
+    HttpRequest(Box<http_request::HttpRequestParam>),
+    HttpSession(http_session::HttpSessionParam),
+    GraphQL(Box<graphql::GraphQLParam>),
+    Grpc(Box<grpc::GrpcParam>),
+    WebSocketConnect(websocket::WebSocketConnectParam),
+    WebSocketSend(websocket::WebSocketSendParam),
+    WebSocketReceive(websocket::WebSocketReceiveParam),
+    WebSocketClose(websocket::WebSocketCloseParam),
+    TcpSend(socket::SocketSendParam),
+    UdpSend(socket::SocketSendParam),
+    Sql(sql::SqlParam),
+    Redis(redis::RedisParam),
+    Exec(exec::ExecParam),
+    Rendezvous(rendezvous::RendezvousParam),
     Sleep(sleep::SleepParam),
     LoadGen(load_gen::LoadGenParam),
     RunRhaiCode(rhai_code::RhaiCodeParam),
@@ -0,0 +1,172 @@
+use std::collections::BTreeMap;
+use std::process::Stdio;
+use std::time::Duration;
+
+use rhai::Dynamic;
+use serde::{Deserialize, Serialize};
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+use tokio::time::Instant;
+
+use crate::kv_store::commands::Sender;
+
+use super::kv::set_value;
+use super::metrics::{append_metric, should_collect_metrics, time_stamp};
+use super::result::*;
+use super::timeout::function_timeout;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ExecMetric {
+    pub command: String,
+
+    /// When did the process start
+    pub time_stamp: String,
+
+    /// Time from starting the process until it exited.
+    pub elapsed_time: u128,
+
+    /// Not set when the process didn't exit by itself.
+    pub exit_code: Option<i32>,
+
+    pub success: bool,
+
+    /// Why the function failed, if it did.
+    pub error: String,
+
+    pub tag: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ExecParam {
+    /// Program to run, looked up in the `PATH` unless it's a path.
+    pub command: String,
+
+    #[serde(default)]
+    pub args: Vec<String>,
+
+    /// Variables added to the environment of the process.
+    #[serde(default)]
+    pub env: BTreeMap<String, String>,
+
+    /// Text written to the standard input of the process, which is closed
+    /// right away when not set.
+    #[serde(default)]
+    pub stdin: Option<String>,
+
+    #[serde(default)]
+    pub timeout: Option<u64>,
+
+    #[serde(default)]
+    pub tag: Option<String>,
+}
+
+/// What the process wrote before it exited.
+#[derive(Default)]
+struct Outcome {
+    exit_code: Option<i32>,
+    stdout: Vec<u8>,
+    stderr: Vec<u8>,
+}
+
+async fn run_process(param: &ExecParam, timeout: Duration) -> std::result::Result<Outcome, String> {
+    let mut child = Command::new(&param.command)
+        .args(&param.args)
+        .envs(&param.env)
+        .stdin(match param.stdin {
+            Some(_) => Stdio::piped(),
+            None => Stdio::null(),
+        })
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .map_err(|err| format!("Failed to start {}: {err}", param.command))?;
+
+    // The input is written while the output is read, so that a process that
+    // writes before it has read all of its input doesn't block.
+    let stdin = child.stdin.take();
+    let input = param.stdin.clone().unwrap_or_default();
+    let write_stdin = async move {
+        if let Some(mut stdin) = stdin {
+            // A process that exits without reading its input isn't an error.
+            let _ = stdin.write_all(input.as_bytes()).await;
+        }
+    };
+
+    let (_, output) = tokio::time::timeout(
+        timeout,
+        futures::future::join(write_stdin, child.wait_with_output()),
+    )
+    .await
+    .map_err(|_| "the process timed out and was killed".to_string())?;
+    let output = output.map_err(|err| format!("Failed to wait for the process: {err}"))?;
+
+    Ok(Outcome {
+        exit_code: output.status.code(),
+        stdout: output.stdout,
+        stderr: output.stderr,
+    })
+}
+
+/// Runs the process and stores its exit code in `exec_exit_code` and its
+/// output in `exec_stdout` and `exec_stderr`. The function fails unless the
+/// process exits with 0, with the reason in `exec_error`.
+pub async fn exec(
+    param: ExecParam,
+    timeout: Option<Duration>,
+    global_kv_tx: Sender,
+    local_kv_tx: Sender,
+) -> FunctionResult {
+    let timeout = function_timeout(param.timeout, timeout);
+
+    let time_stamp = time_stamp();
+    let started_at = Instant::now();
+    let result = run_process(&param, timeout).await;
+    let elapsed_time = started_at.elapsed();
+
+    let (outcome, error) = match result {
+        Ok(outcome) => {
+            let error = match outcome.exit_code {
+                Some(0) => None,
+                Some(code) => Some(format!("the process exited with {code}")),
+                None => Some("the process was terminated by a signal".to_string()),
+            };
+            (outcome, error)
+        }
+        Err(err) => (Outcome::default(), Some(err)),
+    };
+
+    let exit_code = match outcome.exit_code {
+        Some(code) => Dynamic::from_int(code.into()),
+        None => Dynamic::UNIT,
+    };
+    set_value(&local_kv_tx, "exec_exit_code", exit_code).await?;
+    let stdout = String::from_utf8_lossy(&outcome.stdout).into_owned();
+    set_value(&local_kv_tx, "exec_stdout", Dynamic::from(stdout)).await?;
+    let stderr = String::from_utf8_lossy(&outcome.stderr).into_owned();
+    set_value(&local_kv_tx, "exec_stderr", Dynamic::from(stderr)).await?;
+    set_value(
+        &local_kv_tx,
+        "exec_error",
+        Dynamic::from(error.clone().unwrap_or_default()),
+    )
+    .await?;
+
+    if should_collect_metrics(&global_kv_tx).await? {
+        let metric = ExecMetric {
+            command: param.command,
+            time_stamp,
+            elapsed_time: elapsed_time.as_millis(),
+            exit_code: outcome.exit_code,
+            success: error.is_none(),
+            error: error.clone().unwrap_or_default(),
+            tag: param.tag.unwrap_or_default(),
+        };
+        append_metric(&global_kv_tx, metric).await?;
+    }
+
+    match error {
+        Some(_) => Ok(FunctionStatus::Failed),
+        None => Ok(FunctionStatus::Passed),
+    }
+}
@@ -0,0 +1,143 @@
+use std::time::Duration;
+
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+use crate::kv_store::commands::Sender;
+
+use super::http_auth::HttpAuth;
+use super::http_request::{
+    send_request, ExpectedStatus, HttpBody, HttpRequestParam, KeyValue, ProxyConfig, ResponseMode,
+    TlsConfig,
+};
+use super::result::*;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct GraphQLParam {
+    pub url: String,
+
+    /// The GraphQL document, either inline or read from `query_path`.
+    #[serde(default)]
+    pub query: Option<String>,
+
+    #[serde(default)]
+    pub query_path: Option<String>,
+
+    #[serde(default)]
+    pub variables: Option<serde_json::Value>,
+
+    /// Name of the operation to execute, which is also used as the tag of the
+    /// metrics. Taken from the query when not set.
+    #[serde(default)]
+    pub operation_name: Option<String>,
+
+    #[serde(default)]
+    pub headers: Vec<KeyValue<String>>,
+
+    #[serde(default)]
+    pub session: Option<String>,
+
+    #[serde(default)]
+    pub timeout: Option<u64>,
+
+    #[serde(default)]
+    pub auth: Option<HttpAuth>,
+
+    /// The status codes of a successful response, in addition to it having
+    /// no `errors`.
+    #[serde(default)]
+    pub expected_status: Vec<ExpectedStatus>,
+
+    /// Either `Text`, `Bytes` or `EventStream`, as the body is needed to check
+    /// for `errors`.
+    #[serde(default)]
+    pub response_mode: ResponseMode,
+
+    #[serde(default)]
+    pub tls: Option<TlsConfig>,
+
+    #[serde(default)]
+    pub proxy: Option<ProxyConfig>,
+}
+
+/// Returns the name of the first named operation in the document.
+fn find_operation_name(query: &str) -> Option<String> {
+    let regex = Regex::new(r"\b(?:query|mutation|subscription)\s+([_A-Za-z][_0-9A-Za-z]*)")
+        .expect("the regex is valid");
+    regex
+        .captures(query)
+        .map(|captures| captures[1].to_string())
+}
+
+/// A GraphQL response is only a success if it has no `errors`, whatever its
+/// status code.
+fn has_no_errors(body: &[u8]) -> bool {
+    match serde_json::from_slice::<serde_json::Value>(body) {
+        Ok(response) => match response.get("errors") {
+            Some(serde_json::Value::Array(errors)) => errors.is_empty(),
+            Some(serde_json::Value::Null) | None => true,
+            Some(_) => false,
+        },
+        Err(_) => false,
+    }
+}
+
+pub async fn make_graphql_request(
+    param: GraphQLParam,
+    timeout: Option<Duration>,
+    global_kv_tx: Sender,
+    local_kv_tx: Sender,
+) -> FunctionResult {
+    if let ResponseMode::Discard | ResponseMode::SaveToFile(_) = param.response_mode {
+        return Err("the response of a GraphQL request must be kept to check its errors".into());
+    }
+
+    let query = match (param.query, &param.query_path) {
+        (Some(query), None) => query,
+        (None, Some(path)) => tokio::fs::read_to_string(path).await?,
+        _ => return Err("exactly one of query and query_path must be set".into()),
+    };
+    let operation_name = param.operation_name.or_else(|| find_operation_name(&query));
+
+    let mut envelope = serde_json::Map::new();
+    envelope.insert("query".into(), query.into());
+    if let Some(variables) = param.variables {
+        envelope.insert("variables".into(), variables);
+    }
+    if let Some(operation_name) = &operation_name {
+        envelope.insert("operationName".into(), operation_name.clone().into());
+    }
+
+    let mut headers = param.headers;
+    if !headers
+        .iter()
+        .any(|KeyValue(key, _)| key.eq_ignore_ascii_case("accept"))
+    {
+        headers.push(KeyValue("Accept".into(), "application/json".into()));
+    }
+
+    let request = HttpRequestParam {
+        url: param.url,
+        method: "POST".into(),
+        headers,
+        body: HttpBody::Json(envelope.into()),
+        expected_status: param.expected_status,
+        response_mode: param.response_mode,
+        session: param.session,
+        timeout: param.timeout,
+        tls: param.tls,
+        proxy: param.proxy,
+        auth: param.auth,
+        tag: operation_name,
+        ..Default::default()
+    };
+
+    send_request(
+        request,
+        timeout,
+        global_kv_tx,
+        local_kv_tx,
+        Some(has_no_errors),
+    )
+    .await
+}
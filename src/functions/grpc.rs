@@ -0,0 +1,635 @@
+use std::path::Path;
+use std::time::Duration;
+
+use futures::io::AsyncReadExt;
+use isahc::config::VersionNegotiation;
+use isahc::http::{HeaderMap, StatusCode};
+use isahc::prelude::*;
+use isahc::{AsyncBody, Request};
+use percent_encoding::percent_decode_str;
+use prost::Message;
+use prost_reflect::{DescriptorPool, DynamicMessage, MessageDescriptor, SerializeOptions};
+use rhai::Dynamic;
+use serde::{Deserialize, Serialize};
+use tokio::time::Instant;
+
+use crate::kv_store::commands::Sender;
+
+use super::http_request::{
+    apply_proxy, apply_tls, get_client, get_http_config, KeyValue, TlsConfig,
+};
+use super::kv::{get_value, set_value, ConnectionPolicy};
+use super::metrics::{append_metric, should_collect_metrics, time_stamp};
+use super::result::*;
+use super::timeout::function_timeout;
+
+/// Status codes of gRPC that are set by the client itself.
+const STATUS_UNKNOWN: i64 = 2;
+const STATUS_DEADLINE_EXCEEDED: i64 = 4;
+const STATUS_INTERNAL: i64 = 13;
+const STATUS_UNAVAILABLE: i64 = 14;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct GrpcMetric {
+    pub url: String,
+
+    /// Full name of the method, such as `package.Service/Method`.
+    pub method: String,
+
+    /// When did the call start
+    pub time_stamp: String,
+
+    /// gRPC status code of the call, 0 when it succeeded.
+    pub status_code: i64,
+
+    pub status_message: String,
+
+    /// Time from the start of the call until the status was received.
+    pub elapsed_time: u128,
+
+    /// Size of the encoded messages that were sent and received (in bytes)
+    pub request_size: usize,
+    pub response_size: usize,
+
+    pub messages_sent: usize,
+    pub messages_received: usize,
+
+    pub success: bool,
+
+    pub tag: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct GrpcParam {
+    /// Address of the server, such as `http://localhost:50051`.
+    pub url: String,
+
+    /// The .proto file that defines the service. Its imports are looked up
+    /// in `include_paths`, which default to the directory of the file.
+    #[serde(default)]
+    pub proto_path: Option<String>,
+
+    #[serde(default)]
+    pub include_paths: Vec<String>,
+
+    /// A compiled descriptor set, as written by `protoc --descriptor_set_out`,
+    /// which can be used instead of `proto_path`.
+    #[serde(default)]
+    pub descriptor_set_path: Option<String>,
+
+    /// Full name of the service, such as `package.Service`.
+    pub service: String,
+
+    pub method: String,
+
+    /// The request message as json. Methods with a client stream take an
+    /// array of messages, which are all sent before the response is read.
+    #[serde(default)]
+    pub request: serde_json::Value,
+
+    /// Metadata sent as the headers of the call.
+    #[serde(default)]
+    pub metadata: Vec<KeyValue<String>>,
+
+    #[serde(default)]
+    pub connection_policy: ConnectionPolicy,
+
+    #[serde(default)]
+    pub timeout: Option<u64>,
+
+    #[serde(default)]
+    pub tls: Option<TlsConfig>,
+
+    #[serde(default)]
+    pub tag: Option<String>,
+}
+
+/// Status of the call along with what was received.
+struct Outcome {
+    status_code: i64,
+    status_message: String,
+    responses: Vec<serde_json::Value>,
+    response_size: usize,
+}
+
+impl Outcome {
+    fn error(status_code: i64, status_message: String) -> Self {
+        Outcome {
+            status_code,
+            status_message,
+            responses: Vec::new(),
+            response_size: 0,
+        }
+    }
+}
+
+/// Returns the descriptors of the proto file or the descriptor set. They are
+/// kept in the global kv store, so each file is only parsed once.
+async fn get_descriptors(param: &GrpcParam, global_kv_tx: &Sender) -> Result<DescriptorPool> {
+    let path = match (&param.proto_path, &param.descriptor_set_path) {
+        (Some(path), None) | (None, Some(path)) => path,
+        _ => return Err("exactly one of proto_path and descriptor_set_path must be set".into()),
+    };
+    let key = format!("grpc_descriptors:{path}");
+    if let Some(pool) = get_value(global_kv_tx, &key)
+        .await?
+        .try_cast::<DescriptorPool>()
+    {
+        return Ok(pool);
+    }
+
+    let pool = match &param.proto_path {
+        Some(proto_path) => {
+            let mut include_paths = param.include_paths.clone();
+            if include_paths.is_empty() {
+                let parent = Path::new(proto_path).parent().unwrap_or(Path::new(""));
+                include_paths.push(parent.to_string_lossy().into_owned());
+            }
+
+            // The imported files are needed as well, which are only part of
+            // the parsed files.
+            let parsed = protobuf_parse::Parser::new()
+                .pure()
+                .includes(&include_paths)
+                .input(proto_path)
+                .parse_and_typecheck()
+                .map_err(|err| format!("failed to parse {proto_path}: {err:#}"))?;
+            let mut file_descriptor_set = protobuf::descriptor::FileDescriptorSet::new();
+            file_descriptor_set.file = parsed.file_descriptors;
+            DescriptorPool::decode(
+                protobuf::Message::write_to_bytes(&file_descriptor_set)?.as_slice(),
+            )?
+        }
+        None => DescriptorPool::decode(tokio::fs::read(path).await?.as_slice())?,
+    };
+
+    set_value(global_kv_tx, &key, Dynamic::from(pool.clone())).await?;
+    Ok(pool)
+}
+
+/// Encodes the messages with the length prefix of gRPC.
+fn encode_messages(
+    descriptor: MessageDescriptor,
+    messages: &[serde_json::Value],
+) -> Result<Vec<u8>> {
+    let mut body = Vec::new();
+    for message in messages {
+        let message = DynamicMessage::deserialize(descriptor.clone(), message)
+            .map_err(|err| format!("invalid {} message: {err}", descriptor.full_name()))?;
+        let message = message.encode_to_vec();
+
+        body.push(0);
+        body.extend_from_slice(&(message.len() as u32).to_be_bytes());
+        body.extend_from_slice(&message);
+    }
+
+    Ok(body)
+}
+
+/// Decodes the length prefixed messages of the response body into json.
+fn decode_messages(
+    descriptor: MessageDescriptor,
+    mut body: &[u8],
+) -> std::result::Result<Vec<serde_json::Value>, String> {
+    // Default values are kept, so that every field can be read from Rhai.
+    let options = SerializeOptions::new().skip_default_fields(false);
+
+    let mut messages = Vec::new();
+    while !body.is_empty() {
+        let [compressed, b1, b2, b3, b4, rest @ ..] = body else {
+            return Err("truncated message in the response".into());
+        };
+        if *compressed != 0 {
+            return Err("compressed messages are not supported".into());
+        }
+        let len = u32::from_be_bytes([*b1, *b2, *b3, *b4]) as usize;
+        if rest.len() < len {
+            return Err("truncated message in the response".into());
+        }
+
+        let message = DynamicMessage::decode(descriptor.clone(), &rest[..len])
+            .map_err(|err| format!("invalid {} message: {err}", descriptor.full_name()))?;
+        let message = message
+            .serialize_with_options(serde_json::value::Serializer, &options)
+            .map_err(|err| err.to_string())?;
+        messages.push(message);
+
+        body = &rest[len..];
+    }
+
+    Ok(messages)
+}
+
+/// Status of a response without a `grpc-status`, as mapped by the gRPC
+/// clients from the HTTP status.
+fn status_from_http(status: StatusCode) -> i64 {
+    match status.as_u16() {
+        400 => STATUS_INTERNAL,
+        401 => 16,
+        403 => 7,
+        404 => 12,
+        429 | 502 | 503 | 504 => STATUS_UNAVAILABLE,
+        _ => STATUS_UNKNOWN,
+    }
+}
+
+/// The status is in the trailers, or in the headers for a response without a
+/// body.
+fn grpc_status(headers: &HeaderMap, trailers: Option<&HeaderMap>) -> Option<(i64, String)> {
+    let headers = match trailers {
+        Some(trailers) if trailers.contains_key("grpc-status") => trailers,
+        _ => headers,
+    };
+    let status_code = headers.get("grpc-status")?.to_str().ok()?.parse().ok()?;
+    let status_message = headers
+        .get("grpc-message")
+        .and_then(|message| message.to_str().ok())
+        .map(|message| percent_decode_str(message).decode_utf8_lossy().into_owned())
+        .unwrap_or_default();
+
+    Some((status_code, status_message))
+}
+
+/// Formats the deadline of the `grpc-timeout` header, whose value can't have
+/// more than 8 digits, in the finest unit that fits.
+fn grpc_timeout(timeout: Duration) -> String {
+    const MAX_VALUE: u128 = 99_999_999;
+    let millis = timeout.as_millis();
+    let seconds = timeout.as_secs() as u128;
+    if millis <= MAX_VALUE {
+        format!("{millis}m")
+    } else if seconds <= MAX_VALUE {
+        format!("{seconds}S")
+    } else if seconds / 60 <= MAX_VALUE {
+        format!("{}M", seconds / 60)
+    } else {
+        format!("{}H", (seconds / 3600).min(MAX_VALUE))
+    }
+}
+
+/// Calls the method and stores the response message as json in the
+/// `grpc_response` variable, along with `grpc_status_code` and
+/// `grpc_status_message`. Methods with a server stream store all the messages
+/// in `grpc_responses` as well. The call fails unless the status is OK.
+pub async fn make_grpc_request(
+    param: GrpcParam,
+    timeout: Option<Duration>,
+    global_kv_tx: Sender,
+    local_kv_tx: Sender,
+) -> FunctionResult {
+    let timeout = function_timeout(param.timeout, timeout);
+
+    let pool = get_descriptors(&param, &global_kv_tx).await?;
+    let service = pool
+        .get_service_by_name(&param.service)
+        .ok_or_else(|| format!("service '{}' is not defined", param.service))?;
+    let method = service
+        .methods()
+        .find(|method| method.name() == param.method)
+        .ok_or_else(|| format!("method '{}' is not defined", param.method))?;
+
+    let requests = match param.request {
+        serde_json::Value::Array(requests) if method.is_client_streaming() => requests,
+        serde_json::Value::Null => vec![serde_json::Value::Object(Default::default())],
+        request => vec![request],
+    };
+    let body = encode_messages(method.input(), &requests)?;
+    let request_size = body.len();
+
+    let url = format!(
+        "{}/{}/{}",
+        param.url.trim_end_matches('/'),
+        service.full_name(),
+        method.name()
+    );
+    let metrics_method = format!("{}/{}", service.full_name(), method.name());
+
+    let mut request_builder = Request::post(&url)
+        .version_negotiation(VersionNegotiation::http2())
+        .timeout(timeout)
+        .header("Content-Type", "application/grpc")
+        .header("TE", "trailers")
+        .header("grpc-timeout", grpc_timeout(timeout));
+    for KeyValue(key, value) in &param.metadata {
+        request_builder = request_builder.header(key, value);
+    }
+
+    let http_config = get_http_config(&global_kv_tx).await?;
+    if let Some(proxy) = &http_config.proxy {
+        request_builder = apply_proxy(request_builder, proxy)?;
+    }
+    if let Some(tls) = param.tls.as_ref().or(http_config.tls.as_ref()) {
+        request_builder = apply_tls(request_builder, tls);
+    }
+    let request = request_builder.body(AsyncBody::from(body))?;
+
+    let client = get_client(
+        param.connection_policy,
+        None,
+        &http_config,
+        &global_kv_tx,
+        &local_kv_tx,
+    )
+    .await?;
+
+    let time_stamp = time_stamp();
+    let started_at = Instant::now();
+    let outcome = match client.send_async(request).await {
+        Ok(mut response) => {
+            let mut body = Vec::new();
+            match response.body_mut().read_to_end(&mut body).await {
+                Ok(_) => {
+                    // The trailers have arrived once the body is consumed.
+                    let trailers = response.trailer().try_get();
+                    let status = grpc_status(response.headers(), trailers);
+                    let decoded = decode_messages(method.output(), &body);
+
+                    match (status, decoded) {
+                        (Some((status_code, status_message)), Ok(responses)) => Outcome {
+                            status_code,
+                            status_message,
+                            responses,
+                            response_size: body.len(),
+                        },
+                        (Some(_), Err(err)) => Outcome::error(STATUS_INTERNAL, err),
+                        (None, _) if response.status() != StatusCode::OK => Outcome::error(
+                            status_from_http(response.status()),
+                            format!("HTTP status {}", response.status()),
+                        ),
+                        (None, _) => {
+                            Outcome::error(STATUS_UNKNOWN, "the response has no grpc-status".into())
+                        }
+                    }
+                }
+                Err(err) => Outcome::error(
+                    STATUS_UNAVAILABLE,
+                    format!("Failed to read response body: {err}"),
+                ),
+            }
+        }
+        Err(err) if *err.kind() == isahc::error::ErrorKind::Timeout => {
+            Outcome::error(STATUS_DEADLINE_EXCEEDED, format!("Request failed: {err}"))
+        }
+        Err(err) => Outcome::error(STATUS_UNAVAILABLE, format!("Request failed: {err}")),
+    };
+    let elapsed_time = started_at.elapsed();
+    let success = outcome.status_code == 0;
+
+    let grpc_response = match outcome.responses.last() {
+        Some(response) if success => Dynamic::from(serde_json::to_string(response)?),
+        _ => Dynamic::from(outcome.status_message.clone()),
+    };
+    set_value(&local_kv_tx, "grpc_response", grpc_response).await?;
+    if method.is_server_streaming() {
+        let responses = rhai::serde::to_dynamic(&outcome.responses)?;
+        set_value(&local_kv_tx, "grpc_responses", responses).await?;
+    }
+    set_value(
+        &local_kv_tx,
+        "grpc_status_code",
+        Dynamic::from_int(outcome.status_code),
+    )
+    .await?;
+    set_value(
+        &local_kv_tx,
+        "grpc_status_message",
+        Dynamic::from(outcome.status_message.clone()),
+    )
+    .await?;
+
+    if should_collect_metrics(&global_kv_tx).await? {
+        let metric = GrpcMetric {
+            url: param.url,
+            method: metrics_method,
+            time_stamp,
+            status_code: outcome.status_code,
+            status_message: outcome.status_message,
+            elapsed_time: elapsed_time.as_millis(),
+            request_size,
+            response_size: outcome.response_size,
+            messages_sent: requests.len(),
+            messages_received: outcome.responses.len(),
+            success,
+            tag: param.tag.unwrap_or_default(),
+        };
+        append_metric(&global_kv_tx, metric).await?;
+    }
+
+    if success {
+        Ok(FunctionStatus::Passed)
+    } else {
+        Ok(FunctionStatus::Failed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+    use std::net::SocketAddr;
+
+    use tonic::codegen::{http, BoxFuture, Context, Poll, Service};
+    use tonic::server::{Grpc, NamedService, UnaryService};
+    use tonic_prost::ProstCodec;
+
+    use super::*;
+    use crate::kv_store::store::new as kv_store_new;
+
+    const ECHO_PROTO: &str = r#"
+        syntax = "proto3";
+        package echo;
+
+        message EchoRequest {
+            string message = 1;
+            int32 count = 2;
+        }
+
+        message EchoReply {
+            string message = 1;
+            int32 count = 2;
+            string grpc_timeout = 3;
+        }
+
+        service Echo {
+            rpc Say(EchoRequest) returns (EchoReply);
+            rpc Fail(EchoRequest) returns (EchoReply);
+        }
+    "#;
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    struct EchoRequest {
+        #[prost(string, tag = "1")]
+        message: String,
+        #[prost(int32, tag = "2")]
+        count: i32,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    struct EchoReply {
+        #[prost(string, tag = "1")]
+        message: String,
+        #[prost(int32, tag = "2")]
+        count: i32,
+        #[prost(string, tag = "3")]
+        grpc_timeout: String,
+    }
+
+    /// Echoes the request back along with the `grpc-timeout` it was sent with.
+    struct Say;
+
+    impl UnaryService<EchoRequest> for Say {
+        type Response = EchoReply;
+        type Future = BoxFuture<tonic::Response<EchoReply>, tonic::Status>;
+
+        fn call(&mut self, request: tonic::Request<EchoRequest>) -> Self::Future {
+            let grpc_timeout = request
+                .metadata()
+                .get("grpc-timeout")
+                .and_then(|value| value.to_str().ok())
+                .unwrap_or_default()
+                .to_string();
+            let request = request.into_inner();
+            Box::pin(async move {
+                Ok(tonic::Response::new(EchoReply {
+                    message: request.message,
+                    count: request.count,
+                    grpc_timeout,
+                }))
+            })
+        }
+    }
+
+    struct Fail;
+
+    impl UnaryService<EchoRequest> for Fail {
+        type Response = EchoReply;
+        type Future = BoxFuture<tonic::Response<EchoReply>, tonic::Status>;
+
+        fn call(&mut self, _request: tonic::Request<EchoRequest>) -> Self::Future {
+            Box::pin(async { Err(tonic::Status::not_found("no such echo")) })
+        }
+    }
+
+    #[derive(Clone)]
+    struct EchoServer;
+
+    impl NamedService for EchoServer {
+        const NAME: &'static str = "echo.Echo";
+    }
+
+    impl<B> Service<http::Request<B>> for EchoServer
+    where
+        B: tonic::codegen::Body + Send + 'static,
+        B::Error: Into<tonic::codegen::StdError> + Send + 'static,
+    {
+        type Response = http::Response<tonic::body::Body>;
+        type Error = Infallible;
+        type Future = BoxFuture<Self::Response, Self::Error>;
+
+        fn poll_ready(
+            &mut self,
+            _cx: &mut Context<'_>,
+        ) -> Poll<std::result::Result<(), Infallible>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, request: http::Request<B>) -> Self::Future {
+            let mut grpc = Grpc::new(ProstCodec::<EchoReply, EchoRequest>::default());
+            match request.uri().path() {
+                "/echo.Echo/Say" => Box::pin(async move { Ok(grpc.unary(Say, request).await) }),
+                "/echo.Echo/Fail" => Box::pin(async move { Ok(grpc.unary(Fail, request).await) }),
+                _ => Box::pin(async {
+                    Ok(tonic::Status::unimplemented("unknown method").into_http())
+                }),
+            }
+        }
+    }
+
+    async fn start_echo_server() -> SocketAddr {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(
+            tonic::transport::Server::builder()
+                .add_service(EchoServer)
+                .serve_with_incoming(tonic::transport::server::TcpIncoming::from(listener)),
+        );
+        address
+    }
+
+    fn echo_param(address: SocketAddr, proto_path: &Path, method: &str) -> GrpcParam {
+        GrpcParam {
+            url: format!("http://{address}"),
+            proto_path: Some(proto_path.to_string_lossy().into_owned()),
+            include_paths: Vec::new(),
+            descriptor_set_path: None,
+            service: "echo.Echo".into(),
+            method: method.into(),
+            request: serde_json::json!({"message": "hello", "count": 3}),
+            metadata: Vec::new(),
+            connection_policy: Default::default(),
+            timeout: Some(10),
+            tls: None,
+            tag: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn calls_local_echo_server() {
+        let address = start_echo_server().await;
+        let proto_dir = std::env::temp_dir().join(format!("lorust_grpc_test_{}", address.port()));
+        std::fs::create_dir_all(&proto_dir).unwrap();
+        let proto_path = proto_dir.join("echo.proto");
+        std::fs::write(&proto_path, ECHO_PROTO).unwrap();
+
+        let (_global_kv_handle, global_kv_tx) = kv_store_new().await;
+        let (_local_kv_handle, local_kv_tx) = kv_store_new().await;
+
+        let status = make_grpc_request(
+            echo_param(address, &proto_path, "Say"),
+            None,
+            global_kv_tx.clone(),
+            local_kv_tx.clone(),
+        )
+        .await
+        .unwrap();
+        assert!(matches!(status, FunctionStatus::Passed));
+        let response = get_value(&local_kv_tx, "grpc_response")
+            .await
+            .unwrap()
+            .cast::<String>();
+        let response: serde_json::Value = serde_json::from_str(&response).unwrap();
+        assert_eq!(
+            response,
+            serde_json::json!({"message": "hello", "count": 3, "grpcTimeout": "10000m"})
+        );
+
+        let status = make_grpc_request(
+            echo_param(address, &proto_path, "Fail"),
+            None,
+            global_kv_tx,
+            local_kv_tx.clone(),
+        )
+        .await
+        .unwrap();
+        assert!(matches!(status, FunctionStatus::Failed));
+        let status_code = get_value(&local_kv_tx, "grpc_status_code").await.unwrap();
+        assert_eq!(status_code.as_int().unwrap(), 5);
+        let status_message = get_value(&local_kv_tx, "grpc_status_message")
+            .await
+            .unwrap();
+        assert_eq!(status_message.cast::<String>(), "no such echo");
+
+        std::fs::remove_dir_all(&proto_dir).unwrap();
+    }
+
+    #[test]
+    fn grpc_timeout_fits_in_eight_digits() {
+        assert_eq!(grpc_timeout(Duration::from_millis(1500)), "1500m");
+        assert_eq!(grpc_timeout(Duration::from_millis(99_999_999)), "99999999m");
+        assert_eq!(grpc_timeout(Duration::from_secs(100_000)), "100000S");
+        assert_eq!(
+            grpc_timeout(Duration::from_secs(1_000_000_000)),
+            "16666666M"
+        );
+        assert_eq!(grpc_timeout(Duration::MAX), "99999999H");
+    }
+}
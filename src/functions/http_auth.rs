@@ -0,0 +1,256 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use isahc::{
+    auth::{Authentication, Credentials},
+    http::request::Builder,
+    prelude::*,
+    AsyncReadResponseExt, HttpClient, Request,
+};
+use rhai::Dynamic;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::sync::{oneshot, Mutex};
+use tokio::time::Instant;
+
+use crate::kv_store::commands::{Command, Sender, Value};
+
+use super::http_request::{apply_proxy, apply_tls, HttpMetric, ProxyConfig, TlsConfig};
+use super::metrics::{append_metric, time_stamp};
+use super::result::*;
+
+fn default_refresh_margin() -> u64 {
+    30
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct OAuth2ClientCredentials {
+    pub token_url: String,
+
+    pub client_id: String,
+
+    pub client_secret: String,
+
+    #[serde(default)]
+    pub scope: Option<String>,
+
+    #[serde(default)]
+    pub audience: Option<String>,
+
+    /// Send the client credentials in the request body instead of the
+    /// `Authorization` header.
+    #[serde(default)]
+    pub credentials_in_body: bool,
+
+    /// Number of seconds before the expiry of the token when it gets
+    /// refreshed.
+    #[serde(default = "default_refresh_margin")]
+    pub refresh_margin: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum HttpAuth {
+    Basic { username: String, password: String },
+    Bearer { token: String },
+    Digest { username: String, password: String },
+    OAuth2ClientCredentials(OAuth2ClientCredentials),
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: String,
+
+    #[serde(default)]
+    expires_in: Option<u64>,
+}
+
+struct OAuth2Token {
+    access_token: String,
+    expires_at: Option<Instant>,
+}
+
+/// The token of an OAuth2 client, shared by all the virtual users. The lock is
+/// held while fetching the token, so only one of them hits the token url.
+#[derive(Clone, Default)]
+struct OAuth2TokenCache(Arc<Mutex<Option<OAuth2Token>>>);
+
+pub async fn apply_auth(
+    request_builder: Builder,
+    auth: &HttpAuth,
+    tls: Option<&TlsConfig>,
+    proxy: Option<&ProxyConfig>,
+    timeout: Duration,
+    should_collect_metrics: bool,
+    global_kv_tx: &Sender,
+) -> Result<Builder> {
+    let request_builder = match auth {
+        HttpAuth::Basic { username, password } => request_builder
+            .authentication(Authentication::basic())
+            .credentials(Credentials::new(username, password)),
+        HttpAuth::Digest { username, password } => request_builder
+            .authentication(Authentication::digest())
+            .credentials(Credentials::new(username, password)),
+        HttpAuth::Bearer { token } => {
+            request_builder.header("Authorization", format!("Bearer {token}"))
+        }
+        HttpAuth::OAuth2ClientCredentials(config) => {
+            let token = get_oauth2_token(
+                config,
+                tls,
+                proxy,
+                timeout,
+                should_collect_metrics,
+                global_kv_tx,
+            )
+            .await?;
+            request_builder.header("Authorization", format!("Bearer {token}"))
+        }
+    };
+
+    Ok(request_builder)
+}
+
+/// Returns the cached token of the client, fetching a new one if there is
+/// none or if it's about to expire.
+async fn get_oauth2_token(
+    config: &OAuth2ClientCredentials,
+    tls: Option<&TlsConfig>,
+    proxy: Option<&ProxyConfig>,
+    timeout: Duration,
+    should_collect_metrics: bool,
+    global_kv_tx: &Sender,
+) -> Result<String> {
+    // Every field that changes the token is part of the key. The secret is
+    // hashed to keep it out of the key.
+    let key = format!(
+        "oauth2_token:{}",
+        serde_json::to_string(&(
+            &config.token_url,
+            &config.client_id,
+            hex::encode(Sha256::digest(&config.client_secret)),
+            &config.scope,
+            &config.audience,
+            config.credentials_in_body,
+        ))?
+    );
+
+    let (resp_tx, resp_rx) = oneshot::channel();
+    global_kv_tx
+        .send(Command::GetOrSet {
+            key,
+            value: Dynamic::from(OAuth2TokenCache::default()),
+            resp: resp_tx,
+        })
+        .await?;
+    let cache = match resp_rx.await?? {
+        Value::Dynamic(value) => value.try_cast::<OAuth2TokenCache>(),
+        Value::Array(_) => None,
+    }
+    .ok_or("the OAuth2 token cache has an unexpected type")?;
+
+    let mut token = cache.0.lock().await;
+    let refresh_margin = Duration::from_secs(config.refresh_margin);
+    if let Some(token) = token.as_ref() {
+        match token.expires_at {
+            Some(expires_at) if Instant::now() + refresh_margin >= expires_at => {}
+            _ => return Ok(token.access_token.clone()),
+        }
+    }
+
+    let fetched = fetch_oauth2_token(
+        config,
+        tls,
+        proxy,
+        timeout,
+        should_collect_metrics,
+        global_kv_tx,
+    )
+    .await?;
+    let access_token = fetched.access_token.clone();
+    *token = Some(fetched);
+
+    Ok(access_token)
+}
+
+async fn fetch_oauth2_token(
+    config: &OAuth2ClientCredentials,
+    tls: Option<&TlsConfig>,
+    proxy: Option<&ProxyConfig>,
+    timeout: Duration,
+    should_collect_metrics: bool,
+    global_kv_tx: &Sender,
+) -> Result<OAuth2Token> {
+    let body = {
+        let mut form = form_urlencoded::Serializer::new(String::new());
+        form.append_pair("grant_type", "client_credentials");
+        if let Some(scope) = &config.scope {
+            form.append_pair("scope", scope);
+        }
+        if let Some(audience) = &config.audience {
+            form.append_pair("audience", audience);
+        }
+        if config.credentials_in_body {
+            form.append_pair("client_id", &config.client_id);
+            form.append_pair("client_secret", &config.client_secret);
+        }
+        form.finish()
+    };
+
+    let mut request_builder = Request::post(&config.token_url)
+        .timeout(timeout)
+        .metrics(should_collect_metrics)
+        .header("Content-Type", "application/x-www-form-urlencoded")
+        .header("Accept", "application/json");
+
+    if !config.credentials_in_body {
+        request_builder = request_builder
+            .authentication(Authentication::basic())
+            .credentials(Credentials::new(&config.client_id, &config.client_secret));
+    }
+    if let Some(proxy) = proxy {
+        request_builder = apply_proxy(request_builder, proxy)?;
+    }
+    if let Some(tls) = tls {
+        request_builder = apply_tls(request_builder, tls);
+    }
+
+    let request = request_builder.body(body)?;
+
+    let time_stamp = time_stamp();
+    let started_at = Instant::now();
+    let mut response = HttpClient::new()?.send_async(request).await?;
+    let body = response.text().await?;
+
+    if should_collect_metrics {
+        let mut metric = HttpMetric::from_response(
+            config.token_url.clone(),
+            "POST".into(),
+            time_stamp,
+            &response,
+            body.len(),
+            &body,
+            response.status().is_success(),
+        );
+        metric.tag = "oauth2_token".into();
+
+        append_metric(global_kv_tx, metric).await?;
+    }
+
+    if !response.status().is_success() {
+        return Err(format!(
+            "failed to fetch the OAuth2 token from {}: {} {}",
+            config.token_url,
+            response.status(),
+            body
+        )
+        .into());
+    }
+
+    let token: TokenResponse = serde_json::from_str(&body)?;
+    Ok(OAuth2Token {
+        access_token: token.access_token,
+        expires_at: token
+            .expires_in
+            .map(|expires_in| started_at + Duration::from_secs(expires_in)),
+    })
+}
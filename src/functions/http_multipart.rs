@@ -0,0 +1,93 @@
+use rand::RngCore;
+
+use super::http_request::KeyValue;
+use super::result::*;
+
+/// A `multipart/form-data` body that's built in memory. Every header of a
+/// part is written on its own line, and any text that ends up in a header is
+/// checked so that it can't start a new header or part.
+pub struct MultipartForm {
+    boundary: String,
+    body: Vec<u8>,
+}
+
+/// Returns an error if the text would break out of its header line.
+fn check_header_text(text: &str, what: &str, name: &str) -> Result<()> {
+    if text.contains(['\r', '\n']) {
+        return Err(format!("the {what} of the form part '{name}' contains a line break").into());
+    }
+    Ok(())
+}
+
+/// Quotes a name or filename of the Content-Disposition header, escaping the
+/// quotes the way browsers do.
+fn quote(text: &str) -> String {
+    format!("\"{}\"", text.replace('"', "%22"))
+}
+
+impl MultipartForm {
+    pub fn new() -> MultipartForm {
+        let mut random = [0; 16];
+        rand::thread_rng().fill_bytes(&mut random);
+
+        MultipartForm {
+            boundary: format!("lorust-{}", hex::encode(random)),
+            body: Vec::new(),
+        }
+    }
+
+    pub fn content_type_header(&self) -> String {
+        format!("multipart/form-data; boundary={}", self.boundary)
+    }
+
+    pub fn write_field(&mut self, name: &str, value: &str) -> Result<()> {
+        self.write_part(name, None, None, &[], value.as_bytes())
+    }
+
+    pub fn write_part(
+        &mut self,
+        name: &str,
+        filename: Option<&str>,
+        content_type: Option<&str>,
+        headers: &[KeyValue<String>],
+        content: &[u8],
+    ) -> Result<()> {
+        check_header_text(name, "name", name)?;
+        let mut disposition = format!("form-data; name={}", quote(name));
+        if let Some(filename) = filename {
+            check_header_text(filename, "filename", name)?;
+            disposition.push_str(&format!("; filename={}", quote(filename)));
+        }
+
+        let mut lines = vec![format!("Content-Disposition: {disposition}")];
+        if let Some(content_type) = content_type {
+            check_header_text(content_type, "content type", name)?;
+            lines.push(format!("Content-Type: {content_type}"));
+        }
+        for KeyValue(key, value) in headers {
+            check_header_text(key, "header name", name)?;
+            check_header_text(value, &format!("header '{key}'"), name)?;
+            if key.is_empty() || key.contains(':') {
+                return Err(format!("invalid header '{key}' in the form part '{name}'").into());
+            }
+            lines.push(format!("{key}: {value}"));
+        }
+
+        self.body
+            .extend_from_slice(format!("--{}\r\n", self.boundary).as_bytes());
+        for line in lines {
+            self.body.extend_from_slice(line.as_bytes());
+            self.body.extend_from_slice(b"\r\n");
+        }
+        self.body.extend_from_slice(b"\r\n");
+        self.body.extend_from_slice(content);
+        self.body.extend_from_slice(b"\r\n");
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> Vec<u8> {
+        self.body
+            .extend_from_slice(format!("--{}--\r\n", self.boundary).as_bytes());
+        self.body
+    }
+}
@@ -1,51 +1,40 @@
 use std::collections::BTreeMap;
+use std::net::{IpAddr, SocketAddr};
 use std::str::FromStr;
-use std::time::Duration;
+use std::time::{Duration, SystemTime};
 
-use isahc::http::Method;
+use isahc::http::{request::Builder, Method, Uri};
 use isahc::{
-    config::{RedirectPolicy, SslOption},
+    auth::{Authentication, Credentials},
+    config::{
+        CaCertificate, ClientCertificate, Dialer, DnsCache, PrivateKey, RedirectPolicy, ResolveMap,
+        SslOption, VersionNegotiation,
+    },
+    cookies::{Cookie, CookieJar},
     prelude::*,
     Request,
 };
-use isahc::{AsyncBody, AsyncReadResponseExt, HttpClient};
+use isahc::{AsyncBody, HttpClient, Response};
 
-use form_data_builder::FormData;
+use futures::io::{AllowStdIo, AsyncReadExt};
+use rand::RngCore;
 use rhai::Dynamic;
-use tokio::sync::oneshot;
+use tokio::io::AsyncWriteExt;
 use url_encoded_data::UrlEncodedData;
 
 use serde::{Deserialize, Serialize};
 
-use crate::kv_store::commands::{Command, Sender};
+use crate::kv_store::commands::Sender;
 
+use super::http_auth::{apply_auth, HttpAuth};
+use super::http_multipart::MultipartForm;
+use super::http_session::{get_cookie_jars, get_session};
+use super::http_signing::{sign_request, RequestSigning, SignedBody};
+use super::http_stream::{read_event_stream, EventStreamConfig, StopCondition, StreamMetric};
+use super::kv::{get_or_set_value, get_value, named_key, set_value, ConnectionPolicy};
+use super::metrics::{append_metric, should_collect_metrics, time_stamp};
 use super::result::*;
-
-async fn set_local_value(local_kv_tx: &Sender, key: &str, value: Dynamic) -> Result<()> {
-    let (resp_tx, resp_rx) = oneshot::channel();
-    local_kv_tx
-        .send(Command::Set {
-            key: key.to_string(),
-            value,
-            resp: resp_tx,
-        })
-        .await?;
-    resp_rx.await??;
-    Ok(())
-}
-
-async fn append_metric(global_kv_tx: &Sender, metric: HttpMetric) -> Result<()> {
-    let (resp_tx, resp_rx) = oneshot::channel();
-    global_kv_tx
-        .send(Command::Append {
-            key: "load_gen_metrics".into(),
-            value: Dynamic::from(metric),
-            resp: resp_tx,
-        })
-        .await?;
-    resp_rx.await??;
-    Ok(())
-}
+use super::timeout::function_timeout;
 
 fn headers_to_json(headers: &isahc::http::HeaderMap) -> Result<String> {
     let headers: BTreeMap<String, String> = headers
@@ -60,6 +49,7 @@ fn headers_to_json(headers: &isahc::http::HeaderMap) -> Result<String> {
     Ok(serde_json::to_string(&headers)?)
 }
 
+#[allow(clippy::too_many_arguments)]
 async fn record_http_error(
     url: &str,
     method: &str,
@@ -67,24 +57,25 @@ async fn record_http_error(
     error_message: String,
     status_code: Option<i64>,
     headers_json: Option<String>,
+    tag: Option<&str>,
     should_collect_metrics: bool,
     global_kv_tx: &Sender,
     local_kv_tx: &Sender,
 ) -> Result<()> {
-    set_local_value(
+    set_value(
         local_kv_tx,
         "http_response",
         Dynamic::from(error_message.clone()),
     )
     .await?;
-    set_local_value(
+    set_value(
         local_kv_tx,
         "http_status_code",
         Dynamic::from_int(status_code.unwrap_or(0)),
     )
     .await?;
     let headers_json = headers_json.unwrap_or_else(|| "{}".to_string());
-    set_local_value(
+    set_value(
         local_kv_tx,
         "http_response_headers",
         Dynamic::from(headers_json),
@@ -109,6 +100,11 @@ async fn record_http_error(
             starttransfer_time: 0,
             elapsed_time: 0,
             redirect_time: 0,
+            connection_reused: false,
+            success: false,
+            http_version: String::new(),
+            tag: tag.unwrap_or_default().to_string(),
+            stream: None,
         };
 
         append_metric(global_kv_tx, metric).await?;
@@ -134,8 +130,8 @@ pub struct HttpMetric {
     /// When did the request start
     pub time_stamp: String,
 
-    /// Whenever the status code is not within the range 200 <= 299,
-    /// the response body is collected as a string.
+    /// Whenever the request is not a success, the response body is
+    /// collected as a string.
     pub response_body: String,
 
     pub upload_total: u64,
@@ -165,7 +161,8 @@ pub struct HttpMetric {
     pub namelookup_time: u128,
 
     /// The amount of time taken to establish a connection to the server
-    /// (not including TLS connection time).
+    /// (not including TLS connection time). When a proxy is used, this is the
+    /// time taken to connect to the proxy.
     ///
     /// When a redirect is followed, the time from each request is added
     /// together.
@@ -195,12 +192,118 @@ pub struct HttpMetric {
     /// for all redirection steps including name lookup, connect, pretransfer
     /// and transfer before final transaction was started.
     pub redirect_time: u128,
+
+    /// Whether the request was sent over an already established connection
+    /// instead of opening a new one. This is inferred from a connect time of
+    /// zero, as curl doesn't report it, so it's only a best guess.
+    pub connection_reused: bool,
+
+    /// Whether the status code is one of the expected ones, which are the
+    /// ones within the range 200 <= 299 unless the request sets them.
+    pub success: bool,
+
+    /// HTTP version of the response, such as `HTTP/1.1` or `HTTP/2.0`.
+    pub http_version: String,
+
+    /// Distinguishes the requests that are made on behalf of the flow, such
+    /// as `oauth2_token`, from the ones in the flow, which have no tag.
+    pub tag: String,
+
+    /// Timing of the events when the response is read as an event stream.
+    pub stream: Option<StreamMetric>,
+}
+
+impl HttpMetric {
+    /// Builds the metric of a response that was requested with metrics
+    /// enabled. `body_size` is the number of bytes received, `body` is the part
+    /// of it that was captured.
+    pub fn from_response<T>(
+        url: String,
+        http_verb: String,
+        time_stamp: String,
+        response: &Response<T>,
+        body_size: usize,
+        body: &str,
+        success: bool,
+    ) -> Self {
+        let status = response.status();
+        let http_metrics = response
+            .metrics()
+            .expect("metrics must be set to true in the builder");
+
+        // The body is only collected when the request is not a success.
+        let response_body = if success {
+            String::new()
+        } else {
+            body.to_string()
+        };
+
+        HttpMetric {
+            url,
+            http_verb,
+            status_code: status.as_u16() as i64,
+            response_body_size: body_size,
+            time_stamp,
+            response_body,
+
+            upload_total: http_metrics.upload_progress().0,
+            download_total: http_metrics.download_progress().0,
+            upload_speed: http_metrics.upload_speed(),
+            download_speed: http_metrics.download_speed(),
+
+            namelookup_time: http_metrics.name_lookup_time().as_millis(),
+            connect_time: http_metrics.connect_time().as_millis(),
+            tls_handshake_time: http_metrics.secure_connect_time().as_millis(),
+            starttransfer_time: http_metrics.transfer_start_time().as_millis(),
+            elapsed_time: http_metrics.total_time().as_millis(),
+            redirect_time: http_metrics.redirect_time().as_millis(),
+            connection_reused: http_metrics.connect_time().is_zero(),
+            success,
+            http_version: format!("{:?}", response.version()),
+            tag: String::new(),
+            stream: None,
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum FormDataValue {
     Str(String),
     FilePath(String, String),
+    Part(FormDataPart),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum PartContent {
+    /// Name of a variable of the virtual user. Blobs are sent as they are,
+    /// strings as text and any other value as json.
+    Variable(String),
+
+    /// Number of random bytes to generate.
+    RandomBytes(usize),
+
+    FilePath(String),
+}
+
+fn default_part_content_type() -> String {
+    "application/octet-stream".into()
+}
+
+/// A file part of a multipart form.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FormDataPart {
+    pub content: PartContent,
+
+    /// Defaults to the name of the file for `FilePath`, no filename is sent
+    /// otherwise.
+    #[serde(default)]
+    pub filename: Option<String>,
+
+    #[serde(default = "default_part_content_type")]
+    pub content_type: String,
+
+    #[serde(default)]
+    pub headers: Vec<KeyValue<String>>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -211,16 +314,298 @@ pub enum HttpBody {
     #[default]
     Empty,
     Raw(String),
+    Json(serde_json::Value),
     FormData(Vec<KeyValue<FormDataValue>>),
     FormUrlEncoded(Vec<KeyValue<String>>),
     BinaryOctetFilePath(String),
 }
 
+/// The expected status as written in the flow.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(untagged)]
+enum ExpectedStatusSpec {
+    Code(u16),
+    Range(String),
+}
+
+/// A status code such as `404` or `"404"`, or a range of them such as
+/// `"200-299"` or `"2xx"`. It's validated when the flow is read, so that a
+/// typo doesn't cost a request.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+#[serde(try_from = "ExpectedStatusSpec", into = "ExpectedStatusSpec")]
+pub struct ExpectedStatus {
+    min: u16,
+    max: u16,
+}
+
+impl TryFrom<ExpectedStatusSpec> for ExpectedStatus {
+    type Error = String;
+
+    fn try_from(spec: ExpectedStatusSpec) -> std::result::Result<Self, Self::Error> {
+        let parse = |code: &str| {
+            code.trim()
+                .parse::<u16>()
+                .map_err(|_| format!("invalid expected status code '{code}'"))
+        };
+
+        let (min, max) = match &spec {
+            ExpectedStatusSpec::Code(code) => (*code, *code),
+            ExpectedStatusSpec::Range(range) => match range.split_once('-') {
+                Some((min, max)) => (parse(min)?, parse(max)?),
+                None => match range.trim().strip_suffix("xx") {
+                    Some(class) => {
+                        let class = parse(class)?;
+                        if !(1..=5).contains(&class) {
+                            return Err(format!("invalid expected status class '{range}'"));
+                        }
+                        (class * 100, class * 100 + 99)
+                    }
+                    None => {
+                        let code = parse(range)?;
+                        (code, code)
+                    }
+                },
+            },
+        };
+
+        if !(100..=599).contains(&min) || !(100..=599).contains(&max) || min > max {
+            return Err(match spec {
+                ExpectedStatusSpec::Code(code) => format!("invalid expected status {code}"),
+                ExpectedStatusSpec::Range(range) => format!("invalid expected status '{range}'"),
+            });
+        }
+        Ok(ExpectedStatus { min, max })
+    }
+}
+
+impl From<ExpectedStatus> for ExpectedStatusSpec {
+    fn from(status: ExpectedStatus) -> Self {
+        if status.min == status.max {
+            ExpectedStatusSpec::Code(status.min)
+        } else {
+            ExpectedStatusSpec::Range(format!("{}-{}", status.min, status.max))
+        }
+    }
+}
+
+impl ExpectedStatus {
+    fn matches(&self, status: u16) -> bool {
+        (self.min..=self.max).contains(&status)
+    }
+}
+
+/// Determines how the response body is read and what ends up in the
+/// `http_response` variable.
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub enum ResponseMode {
+    /// The body is decoded with the charset of the Content-Type, or as UTF-8
+    /// when there is none, replacing any invalid sequence.
+    #[default]
+    Text,
+
+    /// The body is stored as a blob.
+    Bytes,
+
+    /// The body is read and counted without being kept in memory.
+    Discard,
+
+    /// The body is streamed to the file at the given path, which is stored in
+    /// `http_response`.
+    SaveToFile(String),
+
+    /// The body is read as a stream of events as it arrives, which are stored
+    /// in `http_events`. The body itself is captured like `Text`.
+    EventStream(EventStreamConfig),
+}
+
+/// HTTP version used for the requests. When not set, HTTP/2 is used if the
+/// server offers it during the TLS handshake and HTTP/1.1 otherwise.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum HttpVersion {
+    /// Only HTTP/1.1 is used, even if the server supports HTTP/2.
+    #[serde(rename = "1.1")]
+    Http11,
+
+    /// HTTP/2 is negotiated with the server over TLS, falling back to
+    /// HTTP/1.1 if the server doesn't support it.
+    #[serde(rename = "2")]
+    Http2,
+
+    /// HTTP/2 is used without negotiation, which also works for plain text
+    /// connections. The request fails if the server doesn't support HTTP/2.
+    #[serde(rename = "2-prior-knowledge")]
+    Http2PriorKnowledge,
+}
+
+impl HttpVersion {
+    fn version_negotiation(self) -> VersionNegotiation {
+        match self {
+            HttpVersion::Http11 => VersionNegotiation::http11(),
+            HttpVersion::Http2 => VersionNegotiation::latest_compatible(),
+            HttpVersion::Http2PriorKnowledge => VersionNegotiation::http2(),
+        }
+    }
+}
+
+fn default_tls_verify() -> bool {
+    true
+}
+
+/// Fails the deserialization when a setting that the http client has no way
+/// to apply is set, so that the flow is rejected when it's read instead of
+/// every request failing during the load test.
+fn reject_unsupported<'de, D, T>(
+    deserializer: D,
+    name: &str,
+) -> std::result::Result<Option<T>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    match Option::<T>::deserialize(deserializer)? {
+        Some(_) => Err(serde::de::Error::custom(format!(
+            "{name} is not supported by the http client"
+        ))),
+        None => Ok(None),
+    }
+}
+
+fn reject_min_version<'de, D>(deserializer: D) -> std::result::Result<Option<String>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    reject_unsupported(deserializer, "tls min_version")
+}
+
+fn reject_max_concurrent_streams<'de, D>(
+    deserializer: D,
+) -> std::result::Result<Option<usize>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    reject_unsupported(deserializer, "max_concurrent_streams")
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TlsConfig {
+    /// Verify the certificate and the host name of the server.
+    #[serde(default = "default_tls_verify")]
+    pub verify: bool,
+
+    /// Path to a PEM bundle of the CA certificates used for the verification,
+    /// instead of the system ones.
+    #[serde(default)]
+    pub ca_file: Option<String>,
+
+    /// Path to the client certificate. PKCS#12 files are detected by the
+    /// `.p12` or `.pfx` extension, anything else is read as PEM.
+    #[serde(default)]
+    pub client_cert: Option<String>,
+
+    /// Path to the PEM private key of the client certificate.
+    #[serde(default)]
+    pub client_key: Option<String>,
+
+    /// Password of the private key or the PKCS#12 file.
+    #[serde(default)]
+    pub client_key_password: Option<String>,
+
+    /// Server name sent in the TLS handshake instead of the host of the url.
+    /// The connection is still made to the host of the url, which can't go
+    /// through a proxy.
+    #[serde(default)]
+    pub sni: Option<String>,
+
+    /// Minimum TLS version. The http client has no way to set it, so a flow
+    /// that sets it is rejected instead of silently allowing older versions.
+    #[serde(default, deserialize_with = "reject_min_version")]
+    pub min_version: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ProxyConfig {
+    /// Url of the proxy such as `http://proxy:3128`, `https://proxy:3129` or
+    /// `socks5h://proxy:1080`. Requests are sent directly when not set, which
+    /// allows a request to opt out of the flow wide proxy.
+    #[serde(default)]
+    pub url: Option<String>,
+
+    #[serde(default)]
+    pub username: Option<String>,
+
+    #[serde(default)]
+    pub password: Option<String>,
+
+    /// Hosts that are accessed directly instead of through the proxy.
+    #[serde(default)]
+    pub no_proxy: Vec<String>,
+}
+
+/// Aborts the transfer when it's slower than `bytes_per_second` for
+/// `duration` seconds.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct LowSpeedLimit {
+    pub bytes_per_second: u32,
+    pub duration: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub enum DnsCacheConfig {
+    Disable,
+
+    /// Number of seconds the resolved addresses are cached for.
+    Timeout(u64),
+
+    Forever,
+}
+
+/// Flow wide defaults for the http requests. Every field can be overridden
+/// by the field with the same name in the request.
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct HttpConfig {
+    #[serde(default)]
+    pub tls: Option<TlsConfig>,
+
+    #[serde(default)]
+    pub proxy: Option<ProxyConfig>,
+
+    #[serde(default)]
+    pub http_version: Option<HttpVersion>,
+
+    /// Maximum number of connections that a client opens to the same host.
+    /// With HTTP/2 the requests beyond it are multiplexed over the existing
+    /// connections. Only available flow wide, as it applies to the clients.
+    #[serde(default)]
+    pub max_connections_per_host: Option<usize>,
+
+    /// Maximum number of concurrent HTTP/2 streams per connection. The http
+    /// client has no way to set it, so a flow that sets it is rejected instead
+    /// of silently using the limit announced by the server.
+    #[serde(default, deserialize_with = "reject_max_concurrent_streams")]
+    pub max_concurrent_streams: Option<usize>,
+
+    #[serde(default)]
+    pub connect_timeout: Option<u64>,
+
+    #[serde(default)]
+    pub low_speed_limit: Option<LowSpeedLimit>,
+
+    /// Addresses used for `host:port` instead of resolving the host, e.g.
+    /// `"api.example.com:443": "10.0.0.12"`. Only available flow wide.
+    #[serde(default)]
+    pub resolve: BTreeMap<String, String>,
+
+    /// Caching of the resolved addresses, 60 seconds by default. Only
+    /// available flow wide.
+    #[serde(default)]
+    pub dns_cache: Option<DnsCacheConfig>,
+}
+
 fn default_http_method() -> String {
     "GET".into()
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
 pub struct HttpRequestParam {
     pub url: String,
 
@@ -230,17 +615,474 @@ pub struct HttpRequestParam {
     #[serde(default)]
     pub headers: Vec<KeyValue<String>>,
 
+    /// Query parameters that are url encoded and appended to the `url`. A key
+    /// can be repeated to send multiple values.
+    #[serde(default)]
+    pub query: Vec<KeyValue<String>>,
+
     #[serde(default)]
     pub body: HttpBody,
 
+    /// Status codes that make the request pass. When empty, any response
+    /// passes and only the 2xx ones are recorded as a success.
+    #[serde(default)]
+    pub expected_status: Vec<ExpectedStatus>,
+
+    #[serde(default)]
+    pub response_mode: ResponseMode,
+
+    /// Maximum number of bytes of the body that are kept in `http_response`
+    /// and in the metrics, the rest of it is only counted.
+    #[serde(default)]
+    pub max_capture_size: Option<usize>,
+
     #[serde(default)]
     pub session: Option<String>,
 
+    #[serde(default)]
+    pub connection_policy: ConnectionPolicy,
+
     #[serde(default)]
     pub timeout: Option<u64>,
 
+    /// Number of seconds to wait for the connection to be established.
+    #[serde(default)]
+    pub connect_timeout: Option<u64>,
+
+    #[serde(default)]
+    pub low_speed_limit: Option<LowSpeedLimit>,
+
     #[serde(default)]
     pub redirect_limit: Option<u32>,
+
+    #[serde(default)]
+    pub tls: Option<TlsConfig>,
+
+    #[serde(default)]
+    pub proxy: Option<ProxyConfig>,
+
+    #[serde(default)]
+    pub http_version: Option<HttpVersion>,
+
+    #[serde(default)]
+    pub auth: Option<HttpAuth>,
+
+    /// Signs the request once its body has been built.
+    #[serde(default)]
+    pub signing: Option<RequestSigning>,
+
+    /// Record the url in the metrics without the query string, so that
+    /// requests to the same endpoint can be grouped together.
+    #[serde(default)]
+    pub metrics_url_without_query: bool,
+
+    /// Recorded in the `tag` field of the metrics.
+    #[serde(default)]
+    pub tag: Option<String>,
+}
+
+/// Appends the url encoded `query` parameters to the query string of `url`,
+/// keeping any query string and fragment that are already present.
+fn merge_query(url: &str, query: &[KeyValue<String>]) -> String {
+    if query.is_empty() {
+        return url.to_string();
+    }
+
+    let (url, fragment) = match url.split_once('#') {
+        Some((url, fragment)) => (url, Some(fragment)),
+        None => (url, None),
+    };
+
+    let mut serializer = form_urlencoded::Serializer::new(String::new());
+    for KeyValue(key, value) in query {
+        serializer.append_pair(key, value);
+    }
+    let encoded = serializer.finish();
+
+    let mut merged = url.to_string();
+    match url.find('?') {
+        Some(index) if index + 1 < url.len() && !url.ends_with('&') => merged.push('&'),
+        Some(_) => {}
+        None => merged.push('?'),
+    }
+    merged.push_str(&encoded);
+
+    if let Some(fragment) = fragment {
+        merged.push('#');
+        merged.push_str(fragment);
+    }
+
+    merged
+}
+
+/// Parses the entries of the `resolve` config into hosts, ports and addresses.
+fn resolve_entries(resolve: &BTreeMap<String, String>) -> Result<Vec<(&str, u16, IpAddr)>> {
+    let mut entries = Vec::new();
+    for (host_port, address) in resolve {
+        let (host, port) = host_port.rsplit_once(':').ok_or_else(|| {
+            format!("resolve entry '{host_port}' must be in the host:port format")
+        })?;
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        let address = IpAddr::from_str(address)
+            .map_err(|err| format!("invalid address '{address}' for '{host_port}': {err}"))?;
+
+        entries.push((host, port.parse()?, address));
+    }
+
+    Ok(entries)
+}
+
+fn resolve_map(resolve: &BTreeMap<String, String>) -> Result<ResolveMap> {
+    let mut resolve_map = ResolveMap::new();
+    for (host, port, address) in resolve_entries(resolve)? {
+        resolve_map = resolve_map.add(host, port, address);
+    }
+
+    Ok(resolve_map)
+}
+
+fn build_client(http_config: &HttpConfig) -> Result<HttpClient> {
+    let mut client_builder = HttpClient::builder();
+    if let Some(max) = http_config.max_connections_per_host {
+        client_builder = client_builder.max_connections_per_host(max);
+    }
+    if !http_config.resolve.is_empty() {
+        client_builder = client_builder.dns_resolve(resolve_map(&http_config.resolve)?);
+    }
+    if let Some(dns_cache) = http_config.dns_cache {
+        client_builder = client_builder.dns_cache(match dns_cache {
+            DnsCacheConfig::Disable => DnsCache::Disable,
+            DnsCacheConfig::Timeout(secs) => DnsCache::Timeout(Duration::from_secs(secs)),
+            DnsCacheConfig::Forever => DnsCache::Forever,
+        });
+    }
+
+    Ok(client_builder.build()?)
+}
+
+/// Writes a part whose content comes from a variable, random bytes or a file.
+async fn write_form_part(
+    form: &mut MultipartForm,
+    name: &str,
+    part: FormDataPart,
+    local_kv_tx: &Sender,
+) -> Result<()> {
+    let mut filename = part.filename;
+    let content = match part.content {
+        PartContent::Variable(variable) => {
+            let value = get_value(local_kv_tx, &variable).await?;
+            if value.is_unit() {
+                return Err(
+                    format!("variable '{variable}' of the form part '{name}' is not set").into(),
+                );
+            }
+
+            if value.is_blob() {
+                value.cast::<rhai::Blob>()
+            } else if value.is_string() {
+                value.cast::<String>().into_bytes()
+            } else {
+                serde_json::to_vec(&value)?
+            }
+        }
+        PartContent::RandomBytes(size) => {
+            let mut content = vec![0; size];
+            rand::thread_rng().fill_bytes(&mut content);
+            content
+        }
+        PartContent::FilePath(path) => {
+            if filename.is_none() {
+                filename = file_name(&path);
+            }
+            tokio::fs::read(&path).await?
+        }
+    };
+
+    form.write_part(
+        name,
+        filename.as_deref(),
+        Some(&part.content_type),
+        &part.headers,
+        &content,
+    )
+}
+
+fn file_name(path: &str) -> Option<String> {
+    std::path::Path::new(path)
+        .file_name()
+        .map(|filename| filename.to_string_lossy().into_owned())
+}
+
+/// Returns the client from the kv store that matches the connection policy,
+/// building and storing a new one if there is none yet.
+pub async fn get_client(
+    policy: ConnectionPolicy,
+    session: Option<&str>,
+    http_config: &HttpConfig,
+    global_kv_tx: &Sender,
+    local_kv_tx: &Sender,
+) -> Result<HttpClient> {
+    let (kv_tx, key) = match policy {
+        ConnectionPolicy::PerRequest => return build_client(http_config),
+        ConnectionPolicy::Shared => (global_kv_tx, "http_client".to_string()),
+        ConnectionPolicy::PerUser => (local_kv_tx, named_key("http_client", session)),
+    };
+
+    if let Some(client) = get_value(kv_tx, &key).await?.try_cast::<HttpClient>() {
+        return Ok(client);
+    }
+
+    // Virtual users that start at the same time may all build a client, but
+    // only the first one to be stored is used by all of them.
+    let client = build_client(http_config)?;
+    let client = get_or_set_value(kv_tx, &key, Dynamic::from(client))
+        .await?
+        .try_cast::<HttpClient>()
+        .ok_or("the stored http client has an unexpected type")?;
+    Ok(client)
+}
+
+/// Returns the flow wide http config, if one was provided.
+pub async fn get_http_config(global_kv_tx: &Sender) -> Result<HttpConfig> {
+    let config = get_value(global_kv_tx, "http_config").await?;
+    Ok(config.try_cast().unwrap_or_default())
+}
+
+fn client_certificate(tls: &TlsConfig, path: &str) -> ClientCertificate {
+    let password = tls.client_key_password.clone();
+    let lowercase_path = path.to_lowercase();
+
+    if lowercase_path.ends_with(".p12") || lowercase_path.ends_with(".pfx") {
+        return ClientCertificate::pkcs12_file(path, password);
+    }
+
+    let private_key = tls
+        .client_key
+        .as_ref()
+        .map(|key| PrivateKey::pem_file(key, password));
+    ClientCertificate::pem_file(path, private_key)
+}
+
+pub fn apply_proxy(request_builder: Builder, proxy: &ProxyConfig) -> Result<Builder> {
+    let proxy_url = match &proxy.url {
+        Some(url) => Some(Uri::from_str(url)?),
+        None => None,
+    };
+    let mut request_builder = request_builder.proxy(proxy_url);
+
+    if !proxy.no_proxy.is_empty() {
+        request_builder = request_builder.proxy_blacklist(proxy.no_proxy.clone());
+    }
+
+    if let Some(username) = &proxy.username {
+        let password = proxy.password.clone().unwrap_or_default();
+        request_builder = request_builder
+            .proxy_authentication(Authentication::basic())
+            .proxy_credentials(Credentials::new(username, password));
+    }
+
+    Ok(request_builder)
+}
+
+/// Applies everything in the TLS config except for the `sni`, which depends on
+/// the url of the request.
+pub fn apply_tls(mut request_builder: Builder, tls: &TlsConfig) -> Builder {
+    if !tls.verify {
+        request_builder = request_builder.ssl_options(
+            SslOption::DANGER_ACCEPT_INVALID_CERTS | SslOption::DANGER_ACCEPT_INVALID_HOSTS,
+        );
+    }
+    if let Some(ca_file) = &tls.ca_file {
+        request_builder = request_builder.ssl_ca_certificate(CaCertificate::file(ca_file));
+    }
+    if let Some(client_cert) = &tls.client_cert {
+        request_builder =
+            request_builder.ssl_client_certificate(client_certificate(tls, client_cert));
+    }
+
+    request_builder
+}
+
+/// Returns the address to connect to for the host and port, which is looked
+/// up in the `resolve` config before the DNS.
+pub async fn resolve_address(
+    host: &str,
+    port: u16,
+    resolve: &BTreeMap<String, String>,
+) -> Result<SocketAddr> {
+    let resolved = resolve_entries(resolve)?
+        .into_iter()
+        .find(|(resolve_host, resolve_port, _)| {
+            resolve_host.eq_ignore_ascii_case(host) && *resolve_port == port
+        });
+    Ok(match resolved {
+        Some((_, _, address)) => SocketAddr::new(address, port),
+        None => tokio::net::lookup_host((host, port))
+            .await?
+            .next()
+            .ok_or_else(|| format!("could not resolve {host}"))?,
+    })
+}
+
+/// Rewrites the host of the url to the `sni` name and returns the address of
+/// the original host to connect to, along with the original authority. The
+/// host is looked up in the `resolve` config before the DNS.
+async fn override_sni(
+    url: &str,
+    sni: &str,
+    resolve: &BTreeMap<String, String>,
+) -> Result<(String, SocketAddr, String)> {
+    let uri = Uri::from_str(url)?;
+    let host = uri.host().ok_or("url has no host")?;
+    let port = match (uri.port_u16(), uri.scheme_str()) {
+        (Some(port), _) => port,
+        (None, Some("http")) => 80,
+        _ => 443,
+    };
+    let authority = uri
+        .authority()
+        .map(|authority| authority.to_string())
+        .unwrap_or_default();
+
+    let address = resolve_address(host, port, resolve).await?;
+
+    let sni_authority = match uri.port_u16() {
+        Some(port) => format!("{sni}:{port}"),
+        None => sni.to_string(),
+    };
+    let mut parts = uri.into_parts();
+    parts.authority = Some(sni_authority.parse()?);
+    let url = Uri::from_parts(parts)?.to_string();
+
+    Ok((url, address, authority))
+}
+
+/// Merges the cookies of the jar for the url into the Cookie header, as a
+/// request must not have more than one.
+fn add_cookie_header(headers: &mut Vec<KeyValue<String>>, cookie_jar: &CookieJar, uri: &Uri) {
+    let mut cookies: Vec<String> = headers
+        .iter()
+        .filter(|KeyValue(key, _)| key.eq_ignore_ascii_case("cookie"))
+        .map(|KeyValue(_, value)| value.clone())
+        .collect();
+    cookies.extend(
+        cookie_jar
+            .get_for_uri(uri)
+            .into_iter()
+            .map(|cookie| format!("{}={}", cookie.name(), cookie.value())),
+    );
+
+    if !cookies.is_empty() {
+        headers.retain(|KeyValue(key, _)| !key.eq_ignore_ascii_case("cookie"));
+        headers.push(KeyValue("Cookie".into(), cookies.join("; ")));
+    }
+}
+
+/// Parses a Set-Cookie header, which isahc only does internally.
+fn parse_set_cookie(header: &str) -> Option<Cookie> {
+    let mut attributes = header.split(';');
+    let (name, value) = attributes.next()?.split_once('=')?;
+    let mut builder = Cookie::builder(name.trim(), value.trim().trim_matches('"'));
+
+    let mut expiration = None;
+    let mut max_age = None;
+    for attribute in attributes {
+        let (key, value) = attribute.split_once('=').unwrap_or((attribute, ""));
+        let value = value.trim();
+        match key.trim().to_ascii_lowercase().as_str() {
+            "domain" => builder = builder.domain(value.trim_start_matches('.').to_lowercase()),
+            "path" => builder = builder.path(value),
+            "secure" => builder = builder.secure(true),
+            "max-age" => max_age = value.parse::<i64>().ok(),
+            "expires" => expiration = chrono::DateTime::parse_from_rfc2822(value).ok(),
+            _ => {}
+        }
+    }
+
+    // Max-Age takes precedence over Expires.
+    if let Some(max_age) = max_age {
+        let max_age = Duration::from_secs(max_age.max(0) as u64);
+        builder = builder.expiration(SystemTime::now() + max_age);
+    } else if let Some(expiration) = expiration {
+        builder = builder.expiration(expiration);
+    }
+
+    builder.build().ok()
+}
+
+/// Stores the cookies of the response in the jar under the url.
+fn store_cookies(cookie_jar: &CookieJar, headers: &isahc::http::HeaderMap, uri: &Uri) {
+    let cookies = headers
+        .get_all("set-cookie")
+        .iter()
+        .filter_map(|header| header.to_str().ok())
+        .filter_map(parse_set_cookie);
+    for cookie in cookies {
+        // A cookie for another domain is rejected, like isahc does.
+        let _ = cookie_jar.set(cookie, uri);
+    }
+}
+
+/// Decodes the body with the charset of the Content-Type, like isahc does for
+/// text responses, falling back to UTF-8.
+fn decode_text(body: &[u8], headers: &isahc::http::HeaderMap) -> String {
+    let encoding = headers
+        .get("content-type")
+        .and_then(|content_type| content_type.to_str().ok())
+        .and_then(|content_type| {
+            content_type.split(';').skip(1).find_map(|param| {
+                let (key, value) = param.split_once('=')?;
+                key.trim()
+                    .eq_ignore_ascii_case("charset")
+                    .then(|| value.trim().trim_matches('"'))
+            })
+        })
+        .and_then(|charset| encoding_rs::Encoding::for_label(charset.as_bytes()))
+        .unwrap_or(encoding_rs::UTF_8);
+
+    encoding.decode(body).0.into_owned()
+}
+
+/// Reads the whole body of the response according to the mode. Returns the
+/// number of bytes received and the part of the body that was captured.
+async fn read_body(
+    body: &mut AsyncBody,
+    response_mode: &ResponseMode,
+    max_capture_size: Option<usize>,
+) -> std::io::Result<(usize, Vec<u8>)> {
+    let mut file = match response_mode {
+        ResponseMode::SaveToFile(path) => Some(tokio::fs::File::create(path).await?),
+        _ => None,
+    };
+    let max_capture_size = match response_mode {
+        ResponseMode::Text | ResponseMode::Bytes => max_capture_size.unwrap_or(usize::MAX),
+        ResponseMode::Discard | ResponseMode::SaveToFile(_) => 0,
+        ResponseMode::EventStream(_) => unreachable!("event streams are read by read_event_stream"),
+    };
+
+    let mut body_size = 0;
+    let mut captured = Vec::new();
+    let mut buf = vec![0; 16 * 1024];
+    loop {
+        let len = body.read(&mut buf).await?;
+        if len == 0 {
+            break;
+        }
+        body_size += len;
+
+        let capture_len = len.min(max_capture_size - captured.len());
+        captured.extend_from_slice(&buf[..capture_len]);
+
+        if let Some(file) = &mut file {
+            file.write_all(&buf[..len]).await?;
+        }
+    }
+
+    if let Some(file) = &mut file {
+        file.flush().await?;
+    }
+
+    Ok((body_size, captured))
 }
 
 pub async fn make_request(
@@ -249,55 +1091,183 @@ pub async fn make_request(
     global_kv_tx: Sender,
     local_kv_tx: Sender,
 ) -> FunctionResult {
-    // Check if the load_gen_metrics is set.
-    let (resp_tx, resp_rx) = oneshot::channel();
-    global_kv_tx
-        .send(Command::Exists {
-            key: "load_gen_metrics".into(),
-            resp: resp_tx,
-        })
-        .await?;
-    let should_collect_metrics = resp_rx.await??;
+    send_request(param, timeout, global_kv_tx, local_kv_tx, None).await
+}
 
-    // timeout from the parameters of this request
-    let param_timeout = Duration::from_secs(param.timeout.unwrap_or(60));
-    let timeout = match timeout {
-        Some(t) => std::cmp::min(param_timeout, t),
-        None => param_timeout,
-    };
+/// Sends the request like `make_request`. When `validate_body` is given, the
+/// request is only a success if it returns true for the captured body, and
+/// it fails regardless of `expected_status` otherwise.
+pub async fn send_request(
+    param: HttpRequestParam,
+    timeout: Option<Duration>,
+    global_kv_tx: Sender,
+    local_kv_tx: Sender,
+    validate_body: Option<fn(&[u8]) -> bool>,
+) -> FunctionResult {
+    let should_collect_metrics = should_collect_metrics(&global_kv_tx).await?;
+
+    let timeout = function_timeout(param.timeout, timeout);
+
+    let session = param.session.as_deref();
+    let session_param = get_session(session, &local_kv_tx).await?;
+
+    // Requests without a scheme are relative to the base url of the session.
+    let mut url = param.url.clone();
+    if let Some(base_url) = session_param.as_ref().and_then(|s| s.base_url.as_ref()) {
+        if !url.contains("://") {
+            url = format!(
+                "{}/{}",
+                base_url.trim_end_matches('/'),
+                url.trim_start_matches('/')
+            );
+        }
+    }
 
-    let metrics_url = param.url.clone();
+    // The headers of the request take precedence over the session defaults.
+    let mut headers = Vec::new();
+    if let Some(session_param) = session_param {
+        headers.extend(
+            session_param
+                .headers
+                .into_iter()
+                .filter(|KeyValue(key, _)| {
+                    !param
+                        .headers
+                        .iter()
+                        .any(|KeyValue(header, _)| header.eq_ignore_ascii_case(key))
+                }),
+        );
+    }
+    headers.extend(param.headers);
+
+    let url = merge_query(&url, &param.query);
+    let http_config = get_http_config(&global_kv_tx).await?;
+    let tls = param.tls.or(http_config.tls.clone());
+    let proxy = param.proxy.or(http_config.proxy.clone());
+    let http_version = param.http_version.or(http_config.http_version);
+    let connect_timeout = param.connect_timeout.or(http_config.connect_timeout);
+    let low_speed_limit = param.low_speed_limit.or(http_config.low_speed_limit);
+    let metrics_url = if param.metrics_url_without_query {
+        url.split(['?', '#']).next().unwrap_or_default().to_string()
+    } else {
+        url.clone()
+    };
     let metrics_method = param.method.clone();
 
-    let client = HttpClient::builder()
+    let client = get_client(
+        param.connection_policy,
+        session,
+        &http_config,
+        &global_kv_tx,
+        &local_kv_tx,
+    )
+    .await?;
+    let cookie_jar = get_cookie_jars(&local_kv_tx).await?.get(session);
+
+    // The client may be shared between requests, so everything is configured
+    // on the request itself.
+    let mut request_builder = Request::builder()
+        .uri(&url)
+        .method(Method::from_str(&metrics_method)?)
         .timeout(timeout)
         .metrics(should_collect_metrics)
-        .redirect_policy(RedirectPolicy::Limit(param.redirect_limit.unwrap_or(5)))
-        .cookies()
-        // .ssl_options(SslOption::DANGER_ACCEPT_INVALID_CERTS | SslOption::DANGER_ACCEPT_REVOKED_CERTS)
-        .ssl_options(
-            SslOption::DANGER_ACCEPT_INVALID_CERTS | SslOption::DANGER_ACCEPT_INVALID_HOSTS,
-        )
-        .build()
-        .expect("failed to construct HttpClient");
+        .redirect_policy(RedirectPolicy::Limit(param.redirect_limit.unwrap_or(5)));
 
-    let mut request_builder = Request::builder()
-        .uri(metrics_url.clone())
-        .method(Method::from_str(&metrics_method)?);
+    if let Some(connect_timeout) = connect_timeout {
+        request_builder = request_builder.connect_timeout(Duration::from_secs(connect_timeout));
+    }
 
-    for KeyValue(key, value) in param.headers {
-        request_builder = request_builder.header(key, value);
+    if let Some(LowSpeedLimit {
+        bytes_per_second,
+        duration,
+    }) = low_speed_limit
+    {
+        request_builder =
+            request_builder.low_speed_timeout(bytes_per_second, Duration::from_secs(duration));
     }
 
-    if let Some(duration) = param.timeout {
-        request_builder = request_builder.timeout(Duration::from_secs(duration));
+    if let Some(http_version) = http_version {
+        request_builder = request_builder.version_negotiation(http_version.version_negotiation());
     }
 
+    if let Some(proxy) = &proxy {
+        request_builder = apply_proxy(request_builder, proxy)?;
+    }
+
+    // With an `sni`, the request is sent to the `sni` name, so the cookies are
+    // handled here under the original url instead of by isahc.
+    let mut sni_cookie_uri = None;
+    if let Some(tls) = &tls {
+        request_builder = apply_tls(request_builder, tls);
+
+        if let Some(sni) = &tls.sni {
+            if proxy.as_ref().is_some_and(|proxy| proxy.url.is_some()) {
+                return Err("the tls sni can't be used together with a proxy".into());
+            }
+
+            let (sni_url, address, authority) =
+                override_sni(&url, sni, &http_config.resolve).await?;
+            request_builder = request_builder
+                .uri(sni_url)
+                .dial(Dialer::ip_socket(address));
+
+            if !headers
+                .iter()
+                .any(|KeyValue(key, _)| key.eq_ignore_ascii_case("host"))
+            {
+                request_builder = request_builder.header("Host", authority);
+            }
+
+            let uri = Uri::from_str(&url)?;
+            add_cookie_header(&mut headers, &cookie_jar, &uri);
+            sni_cookie_uri = Some(uri);
+        }
+    }
+    if sni_cookie_uri.is_none() {
+        request_builder = request_builder.cookie_jar(cookie_jar.clone());
+    }
+
+    if let Some(auth) = &param.auth {
+        request_builder = apply_auth(
+            request_builder,
+            auth,
+            tls.as_ref(),
+            proxy.as_ref(),
+            timeout,
+            should_collect_metrics,
+            &global_kv_tx,
+        )
+        .await?;
+    }
+
+    // A Content-Type provided by the user always takes precedence over the
+    // one derived from the body.
+    let has_content_type = headers
+        .iter()
+        .any(|KeyValue(key, _)| key.eq_ignore_ascii_case("content-type"));
+
+    for KeyValue(key, value) in headers {
+        request_builder = request_builder.header(key, value);
+    }
+
+    // The body is built before it's handed over to isahc, so that it can be
+    // signed. Files are the exception, they are only ever streamed.
+    let body_file = match &param.body {
+        HttpBody::BinaryOctetFilePath(path) => Some(path.clone()),
+        _ => None,
+    };
     let body = match param.body {
-        HttpBody::Empty => AsyncBody::empty(),
-        HttpBody::Raw(data) => AsyncBody::from(data),
+        HttpBody::Empty => None,
+        HttpBody::Raw(data) => Some(data.into_bytes()),
+        HttpBody::Json(data) => {
+            if !has_content_type {
+                request_builder = request_builder.header("Content-Type", "application/json");
+            }
+
+            Some(serde_json::to_vec(&data)?)
+        }
         HttpBody::FormData(data) => {
-            let mut form = FormData::new(Vec::new());
+            let mut form = MultipartForm::new();
 
             for KeyValue(key, value) in data {
                 match value {
@@ -305,14 +1275,25 @@ pub async fn make_request(
                         form.write_field(&key, &value)?;
                     }
                     FormDataValue::FilePath(path, content_type) => {
-                        form.write_path(&key, &path, &content_type)?;
+                        let content = tokio::fs::read(&path).await?;
+                        let filename = file_name(&path);
+                        form.write_part(
+                            &key,
+                            filename.as_deref(),
+                            Some(&content_type),
+                            &[],
+                            &content,
+                        )?;
+                    }
+                    FormDataValue::Part(part) => {
+                        write_form_part(&mut form, &key, part, &local_kv_tx).await?;
                     }
                 }
             }
 
             request_builder = request_builder.header("Content-Type", form.content_type_header());
 
-            AsyncBody::from(form.finish()?)
+            Some(form.finish())
         }
         HttpBody::FormUrlEncoded(data) => {
             let mut encoded_data = UrlEncodedData::from("");
@@ -321,16 +1302,60 @@ pub async fn make_request(
                 encoded_data.set_one(key, value);
             }
 
-            AsyncBody::from(encoded_data.to_string())
+            if !has_content_type {
+                request_builder =
+                    request_builder.header("Content-Type", "application/x-www-form-urlencoded");
+            }
+
+            Some(encoded_data.to_string().into_bytes())
+        }
+        HttpBody::BinaryOctetFilePath(_) => {
+            if !has_content_type {
+                request_builder =
+                    request_builder.header("Content-Type", "application/octet-stream");
+            }
+
+            None
+        }
+    };
+
+    if let Some(signing) = &param.signing {
+        let signed_body = match (&body, body_file.as_deref()) {
+            (_, Some(path)) => SignedBody::File(path),
+            (Some(bytes), None) => SignedBody::Bytes(bytes),
+            (None, None) => SignedBody::Bytes(&[]),
+        };
+        request_builder = sign_request(request_builder, signing, signed_body).await?;
+    }
+
+    let body = match (body, body_file.as_deref()) {
+        (_, Some(path)) => {
+            // Stream the file from disk instead of reading it into memory. The
+            // body is polled from the isahc agent thread, which has no tokio
+            // runtime, so a plain std file is used for reading.
+            let file = std::fs::File::open(path)?;
+            let length = file.metadata()?.len();
+
+            AsyncBody::from_reader_sized(AllowStdIo::new(file), length)
         }
-        HttpBody::BinaryOctetFilePath(_) => unimplemented!(),
+        (Some(bytes), None) => AsyncBody::from(bytes),
+        (None, None) => AsyncBody::empty(),
     };
 
     let request = request_builder.body(body)?;
 
-    let time_stamp = chrono::Local::now()
-        .format("%Y-%m-%d %H:%M:%S.%f")
-        .to_string();
+    // Compiled before sending, so that an invalid condition is an error of
+    // the flow instead of a failed request.
+    let stop_condition = match &param.response_mode {
+        ResponseMode::EventStream(EventStreamConfig {
+            stop_when: Some(stop_when),
+            ..
+        }) => Some(StopCondition::new(stop_when)?),
+        _ => None,
+    };
+
+    let time_stamp = time_stamp();
+    let started_at = tokio::time::Instant::now();
     let mut response = match client.send_async(request).await {
         Ok(response) => response,
         Err(err) => {
@@ -342,6 +1367,7 @@ pub async fn make_request(
                 error_message,
                 None,
                 None,
+                param.tag.as_deref(),
                 should_collect_metrics,
                 &global_kv_tx,
                 &local_kv_tx,
@@ -352,8 +1378,29 @@ pub async fn make_request(
         }
     };
 
-    // WARNING: The response text() can be read only once.
-    let body = match response.text().await {
+    if let Some(uri) = &sni_cookie_uri {
+        store_cookies(&cookie_jar, response.headers(), uri);
+    }
+
+    // WARNING: The response body can be read only once.
+    let body = match &param.response_mode {
+        ResponseMode::EventStream(config) => read_event_stream(
+            response.body_mut(),
+            config,
+            stop_condition.as_ref(),
+            param.max_capture_size,
+            started_at,
+        )
+        .await
+        .map(|stream| {
+            let events = Some((stream.events, stream.metric));
+            (stream.body_size, stream.captured, events)
+        }),
+        response_mode => read_body(response.body_mut(), response_mode, param.max_capture_size)
+            .await
+            .map(|(body_size, captured)| (body_size, captured, None)),
+    };
+    let (body_size, captured, events) = match body {
         Ok(body) => body,
         Err(err) => {
             let status_code = response.status().as_u16() as i64;
@@ -366,6 +1413,7 @@ pub async fn make_request(
                 error_message,
                 Some(status_code),
                 Some(headers_json),
+                param.tag.as_deref(),
                 should_collect_metrics,
                 &global_kv_tx,
                 &local_kv_tx,
@@ -376,16 +1424,47 @@ pub async fn make_request(
         }
     };
 
-    set_local_value(&local_kv_tx, "http_response", Dynamic::from(body.clone())).await?;
-    set_local_value(
+    let status = response.status();
+    let success = if param.expected_status.is_empty() {
+        status.is_success()
+    } else {
+        param
+            .expected_status
+            .iter()
+            .any(|expected| expected.matches(status.as_u16()))
+    };
+    let success = match validate_body {
+        Some(validate_body) => success && validate_body(&captured),
+        None => success,
+    };
+
+    let body = match param.response_mode {
+        ResponseMode::Text => decode_text(&captured, response.headers()),
+        _ => String::from_utf8_lossy(&captured).into_owned(),
+    };
+    let http_response = match param.response_mode {
+        ResponseMode::Text | ResponseMode::EventStream(_) => Dynamic::from(body.clone()),
+        ResponseMode::Bytes => Dynamic::from_blob(captured),
+        ResponseMode::Discard => Dynamic::from(String::new()),
+        ResponseMode::SaveToFile(path) => Dynamic::from(path),
+    };
+    set_value(&local_kv_tx, "http_response", http_response).await?;
+    let stream_metric = match events {
+        Some((events, metric)) => {
+            set_value(&local_kv_tx, "http_events", Dynamic::from_array(events)).await?;
+            Some(metric)
+        }
+        None => None,
+    };
+    set_value(
         &local_kv_tx,
         "http_status_code",
-        Dynamic::from_int(response.status().as_u16() as i64),
+        Dynamic::from_int(status.as_u16() as i64),
     )
     .await?;
 
     let headers_json = headers_to_json(response.headers())?;
-    set_local_value(
+    set_value(
         &local_kv_tx,
         "http_response_headers",
         Dynamic::from(headers_json),
@@ -394,37 +1473,17 @@ pub async fn make_request(
 
     // Collect metrics if the key is set.
     if should_collect_metrics {
-        let response_body: String = if response.status().is_success() {
-            ""
-        } else {
-            &body
-        }
-        .into();
-
-        let http_metrics = response
-            .metrics()
-            .expect("metrics must be set to true in the builder");
-
-        let metric = HttpMetric {
-            url: metrics_url.clone(),
-            http_verb: metrics_method.clone(),
-            status_code: response.status().as_u16() as i64,
-            response_body_size: body.len(),
+        let mut metric = HttpMetric::from_response(
+            metrics_url.clone(),
+            metrics_method.clone(),
             time_stamp,
-            response_body,
-
-            upload_total: http_metrics.upload_progress().0,
-            download_total: http_metrics.download_progress().0,
-            upload_speed: http_metrics.upload_speed(),
-            download_speed: http_metrics.download_speed(),
-
-            namelookup_time: http_metrics.name_lookup_time().as_millis(),
-            connect_time: http_metrics.connect_time().as_millis(),
-            tls_handshake_time: http_metrics.secure_connect_time().as_millis(),
-            starttransfer_time: http_metrics.transfer_start_time().as_millis(),
-            elapsed_time: http_metrics.total_time().as_millis(),
-            redirect_time: http_metrics.redirect_time().as_millis(),
-        };
+            &response,
+            body_size,
+            &body,
+            success,
+        );
+        metric.tag = param.tag.clone().unwrap_or_default();
+        metric.stream = stream_metric;
 
         append_metric(&global_kv_tx, metric).await?;
     }
@@ -433,5 +1492,9 @@ pub async fn make_request(
     // println!("{:#?}", response.metrics());
     // println!("{:#?}", param.url);
 
-    Ok(FunctionStatus::Passed)
+    if (param.expected_status.is_empty() && validate_body.is_none()) || success {
+        Ok(FunctionStatus::Passed)
+    } else {
+        Ok(FunctionStatus::Failed)
+    }
 }
@@ -0,0 +1,174 @@
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use isahc::cookies::{Cookie, CookieJar};
+use isahc::http::Uri;
+use parking_lot::Mutex;
+use rhai::{Dynamic, EvalAltResult, Map};
+use serde::{Deserialize, Serialize};
+
+use crate::kv_store::commands::Sender;
+
+use super::http_request::KeyValue;
+use super::kv::{get_value, named_key, set_value};
+use super::result::*;
+
+/// Defaults that are applied to every request made with the session.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct HttpSessionParam {
+    /// Name of the session, the default session is used when not set.
+    #[serde(default)]
+    pub name: Option<String>,
+
+    /// Prepended to the url of the requests that don't have a scheme.
+    #[serde(default)]
+    pub base_url: Option<String>,
+
+    /// Sent with every request unless the request sets the same header.
+    #[serde(default)]
+    pub headers: Vec<KeyValue<String>>,
+}
+
+pub async fn configure_session(param: HttpSessionParam, local_kv_tx: Sender) -> FunctionResult {
+    let key = named_key("http_session", param.name.as_deref());
+    set_value(&local_kv_tx, &key, Dynamic::from(param)).await?;
+
+    Ok(FunctionStatus::Passed)
+}
+
+/// Returns the session defaults of the virtual user, if the session has been
+/// configured.
+pub async fn get_session(
+    session: Option<&str>,
+    local_kv_tx: &Sender,
+) -> Result<Option<HttpSessionParam>> {
+    let key = named_key("http_session", session);
+    Ok(get_value(local_kv_tx, &key).await?.try_cast())
+}
+
+/// Cookie jars of all the sessions of a virtual user. The default session is
+/// stored with an empty name.
+#[derive(Clone, Default)]
+pub struct CookieJars(Arc<Mutex<BTreeMap<String, CookieJar>>>);
+
+impl CookieJars {
+    /// Returns the cookie jar of the session, creating an empty one if needed.
+    pub fn get(&self, session: Option<&str>) -> CookieJar {
+        self.0
+            .lock()
+            .entry(session.unwrap_or_default().to_string())
+            .or_default()
+            .clone()
+    }
+
+    fn get_cookie(&self, session: &str, url: &str, name: &str) -> Dynamic {
+        let Ok(uri) = url.parse::<Uri>() else {
+            return Dynamic::UNIT;
+        };
+
+        match self.get(Some(session)).get_by_name(&uri, name) {
+            Some(cookie) => Dynamic::from(cookie.value().to_string()),
+            None => Dynamic::UNIT,
+        }
+    }
+
+    fn get_cookies(&self, session: &str, url: &str) -> Map {
+        let Ok(uri) = url.parse::<Uri>() else {
+            return Map::new();
+        };
+
+        self.get(Some(session))
+            .get_for_uri(&uri)
+            .into_iter()
+            .map(|cookie| {
+                (
+                    cookie.name().into(),
+                    Dynamic::from(cookie.value().to_string()),
+                )
+            })
+            .collect()
+    }
+
+    fn set_cookie(
+        &self,
+        session: &str,
+        url: &str,
+        name: &str,
+        value: &str,
+    ) -> std::result::Result<(), Box<EvalAltResult>> {
+        let uri = url
+            .parse::<Uri>()
+            .map_err(|err| format!("invalid cookie url '{url}': {err}"))?;
+        let cookie = Cookie::builder(name, value)
+            .path("/")
+            .build()
+            .map_err(|err| format!("invalid cookie '{name}': {err}"))?;
+
+        self.get(Some(session))
+            .set(cookie, &uri)
+            .map_err(|err| format!("cookie '{name}' rejected: {err}"))?;
+        Ok(())
+    }
+
+    fn clear_cookies(&self, session: &str) {
+        self.get(Some(session)).clear();
+    }
+}
+
+/// Returns the cookie jars of the virtual user, creating them if needed.
+pub async fn get_cookie_jars(local_kv_tx: &Sender) -> Result<CookieJars> {
+    if let Some(cookie_jars) = get_value(local_kv_tx, "http_cookie_jars")
+        .await?
+        .try_cast::<CookieJars>()
+    {
+        return Ok(cookie_jars);
+    }
+
+    let cookie_jars = CookieJars::default();
+    set_value(
+        local_kv_tx,
+        "http_cookie_jars",
+        Dynamic::from(cookie_jars.clone()),
+    )
+    .await?;
+    Ok(cookie_jars)
+}
+
+/// Registers the functions to read and write the cookies of the sessions.
+/// Every function has a variant that takes the session name as the first
+/// argument, the others operate on the default session.
+pub fn register_cookie_functions(engine: &mut rhai::Engine, cookie_jars: CookieJars) {
+    let jars = cookie_jars.clone();
+    engine.register_fn("get_cookie", move |url: &str, name: &str| {
+        jars.get_cookie("", url, name)
+    });
+    let jars = cookie_jars.clone();
+    engine.register_fn("get_cookie", move |session: &str, url: &str, name: &str| {
+        jars.get_cookie(session, url, name)
+    });
+
+    let jars = cookie_jars.clone();
+    engine.register_fn("get_cookies", move |url: &str| jars.get_cookies("", url));
+    let jars = cookie_jars.clone();
+    engine.register_fn("get_cookies", move |session: &str, url: &str| {
+        jars.get_cookies(session, url)
+    });
+
+    let jars = cookie_jars.clone();
+    engine.register_fn("set_cookie", move |url: &str, name: &str, value: &str| {
+        jars.set_cookie("", url, name, value)
+    });
+    let jars = cookie_jars.clone();
+    engine.register_fn(
+        "set_cookie",
+        move |session: &str, url: &str, name: &str, value: &str| {
+            jars.set_cookie(session, url, name, value)
+        },
+    );
+
+    let jars = cookie_jars.clone();
+    engine.register_fn("clear_cookies", move || jars.clear_cookies(""));
+    engine.register_fn("clear_cookies", move |session: &str| {
+        cookie_jars.clear_cookies(session)
+    });
+}
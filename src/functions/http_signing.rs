@@ -0,0 +1,501 @@
+use std::io::Read;
+
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use isahc::http::{request::Builder, Uri};
+use percent_encoding::percent_decode_str;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256, Sha512};
+
+use super::result::*;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AwsSigV4 {
+    pub access_key_id: String,
+
+    pub secret_access_key: String,
+
+    #[serde(default)]
+    pub session_token: Option<String>,
+
+    pub region: String,
+
+    /// Name of the service such as `execute-api` or `s3`.
+    pub service: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy)]
+pub enum HmacAlgorithm {
+    #[default]
+    Sha256,
+    Sha512,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy)]
+pub enum SignatureEncoding {
+    #[default]
+    Hex,
+    Base64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy)]
+pub enum TimestampFormat {
+    #[default]
+    UnixSeconds,
+    UnixMillis,
+    Rfc3339,
+}
+
+fn default_string_to_sign() -> String {
+    "{method}\n{path_and_query}\n{timestamp}\n{body_sha256}".into()
+}
+
+fn default_header_value() -> String {
+    "{signature}".into()
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct HmacSigning {
+    pub secret: String,
+
+    #[serde(default)]
+    pub algorithm: HmacAlgorithm,
+
+    #[serde(default)]
+    pub encoding: SignatureEncoding,
+
+    /// Header that the signature is sent in.
+    pub header: String,
+
+    /// Value of the signature header, where `{signature}` is replaced with
+    /// the signature.
+    #[serde(default = "default_header_value")]
+    pub header_value: String,
+
+    /// Template of the signed string. `{method}`, `{host}`, `{path}`,
+    /// `{query}`, `{path_and_query}`, `{timestamp}`, `{body}` and
+    /// `{body_sha256}` are replaced with the values of the request.
+    #[serde(default = "default_string_to_sign")]
+    pub string_to_sign: String,
+
+    /// Header that the timestamp is sent in, if the server needs it.
+    #[serde(default)]
+    pub timestamp_header: Option<String>,
+
+    #[serde(default)]
+    pub timestamp_format: TimestampFormat,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum RequestSigning {
+    AwsSigV4(AwsSigV4),
+    Hmac(HmacSigning),
+}
+
+/// The body of the request as it's sent, either in memory or streamed from a
+/// file.
+pub enum SignedBody<'a> {
+    Bytes(&'a [u8]),
+    File(&'a str),
+}
+
+impl SignedBody<'_> {
+    async fn sha256(&self) -> Result<String> {
+        match self {
+            SignedBody::Bytes(bytes) => Ok(hex::encode(Sha256::digest(bytes))),
+            SignedBody::File(path) => {
+                // Hash the file in chunks on a blocking thread, so that it's
+                // never fully in memory and doesn't hold up the runtime.
+                let path = path.to_string();
+                tokio::task::spawn_blocking(move || -> Result<String> {
+                    let mut hasher = Sha256::new();
+                    let mut file = std::fs::File::open(path)?;
+                    let mut buf = vec![0; 64 * 1024];
+                    loop {
+                        let len = file.read(&mut buf)?;
+                        if len == 0 {
+                            break;
+                        }
+                        hasher.update(&buf[..len]);
+                    }
+                    Ok(hex::encode(hasher.finalize()))
+                })
+                .await?
+            }
+        }
+    }
+}
+
+/// Adds the signature headers to the request. Must be called after everything
+/// else that's part of the signature, i.e. the url, the method and the `Host`
+/// header, has been set on the builder.
+pub async fn sign_request(
+    request_builder: Builder,
+    signing: &RequestSigning,
+    body: SignedBody<'_>,
+) -> Result<Builder> {
+    let method = request_builder
+        .method_ref()
+        .map(|method| method.to_string())
+        .unwrap_or_else(|| "GET".into());
+    let uri = request_builder
+        .uri_ref()
+        .ok_or("the request has no valid url to sign")?
+        .clone();
+
+    // The Host header differs from the url when the sni is overridden.
+    let host = match request_builder
+        .headers_ref()
+        .and_then(|headers| headers.get("host"))
+    {
+        Some(host) => Some(host.to_str()?.to_string()),
+        None => None,
+    };
+
+    match signing {
+        RequestSigning::AwsSigV4(config) => {
+            sign_aws_sigv4(request_builder, config, &method, &uri, host, body).await
+        }
+        RequestSigning::Hmac(config) => {
+            sign_hmac(request_builder, config, &method, &uri, host, body).await
+        }
+    }
+}
+
+fn hmac_sha256(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts keys of any size");
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+fn hmac_sha512(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha512>::new_from_slice(key).expect("HMAC accepts keys of any size");
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// Percent encodes everything except the unreserved characters, as required
+/// by AWS.
+fn aws_uri_encode(value: &str, encode_slash: bool) -> String {
+    let mut encoded = String::with_capacity(value.len());
+    for byte in value.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                encoded.push(byte as char)
+            }
+            b'/' if !encode_slash => encoded.push('/'),
+            _ => encoded.push_str(&format!("%{byte:02X}")),
+        }
+    }
+    encoded
+}
+
+/// Returns the host of the url with the port only when it's not the default
+/// one of the scheme, which is what the Host header is set to.
+fn host_header(uri: &Uri) -> Result<String> {
+    let host = uri.host().ok_or("the url has no host")?;
+    let default_port = match uri.scheme_str() {
+        Some("http") => 80,
+        _ => 443,
+    };
+
+    Ok(match uri.port_u16() {
+        Some(port) if port != default_port => format!("{host}:{port}"),
+        _ => host.to_string(),
+    })
+}
+
+/// Returns the canonical request and the list of signed headers. The headers
+/// must be lowercase and sorted by name.
+fn aws_canonical_request(
+    method: &str,
+    uri: &Uri,
+    service: &str,
+    headers: &[(&str, String)],
+    payload_hash: &str,
+) -> (String, String) {
+    // S3 is the only service where the path is not encoded a second time.
+    let path = match uri.path() {
+        "" => "/",
+        path => path,
+    };
+    let canonical_uri = if service == "s3" {
+        path.to_string()
+    } else {
+        aws_uri_encode(path, false)
+    };
+
+    // The parameters are decoded and encoded again, so that they are encoded
+    // the way AWS expects them whatever the encoding of the url.
+    let encode = |value: &str| aws_uri_encode(&percent_decode_str(value).decode_utf8_lossy(), true);
+    let mut query: Vec<(String, String)> = uri
+        .query()
+        .unwrap_or_default()
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| match pair.split_once('=') {
+            Some((key, value)) => (encode(key), encode(value)),
+            None => (encode(pair), String::new()),
+        })
+        .collect();
+    query.sort();
+    let canonical_query = query
+        .iter()
+        .map(|(key, value)| format!("{key}={value}"))
+        .collect::<Vec<_>>()
+        .join("&");
+
+    let canonical_headers: String = headers
+        .iter()
+        .map(|(key, value)| format!("{key}:{value}\n"))
+        .collect();
+    let signed_headers = headers
+        .iter()
+        .map(|(key, _)| *key)
+        .collect::<Vec<_>>()
+        .join(";");
+
+    let canonical_request = format!(
+        "{method}\n{canonical_uri}\n{canonical_query}\n{canonical_headers}\n{signed_headers}\n{payload_hash}"
+    );
+    (canonical_request, signed_headers)
+}
+
+fn aws_signing_key(config: &AwsSigV4, date: &str) -> Vec<u8> {
+    let key = format!("AWS4{}", config.secret_access_key);
+    let key = hmac_sha256(key.as_bytes(), date.as_bytes());
+    let key = hmac_sha256(&key, config.region.as_bytes());
+    let key = hmac_sha256(&key, config.service.as_bytes());
+    hmac_sha256(&key, b"aws4_request")
+}
+
+/// Signs the canonical request for the `YYYYMMDDTHHMMSSZ` timestamp.
+fn aws_signature(config: &AwsSigV4, amz_date: &str, canonical_request: &str) -> String {
+    let date = &amz_date[..8];
+    let scope = format!("{date}/{}/{}/aws4_request", config.region, config.service);
+    let string_to_sign = format!(
+        "AWS4-HMAC-SHA256\n{amz_date}\n{scope}\n{}",
+        hex::encode(Sha256::digest(canonical_request.as_bytes()))
+    );
+
+    let key = aws_signing_key(config, date);
+    hex::encode(hmac_sha256(&key, string_to_sign.as_bytes()))
+}
+
+async fn sign_aws_sigv4(
+    mut request_builder: Builder,
+    config: &AwsSigV4,
+    method: &str,
+    uri: &Uri,
+    host: Option<String>,
+    body: SignedBody<'_>,
+) -> Result<Builder> {
+    let now = chrono::Utc::now();
+    let amz_date = now.format("%Y%m%dT%H%M%SZ").to_string();
+    let date = now.format("%Y%m%d").to_string();
+    let payload_hash = body.sha256().await?;
+
+    // The signed Host header is sent explicitly, so that it's guaranteed to
+    // match the one that was signed.
+    let host = match host {
+        Some(host) => host,
+        None => {
+            let host = host_header(uri)?;
+            request_builder = request_builder.header("Host", &host);
+            host
+        }
+    };
+
+    let mut headers = vec![
+        ("host", host.trim().to_string()),
+        ("x-amz-content-sha256", payload_hash.clone()),
+        ("x-amz-date", amz_date.clone()),
+    ];
+    if let Some(session_token) = &config.session_token {
+        headers.push(("x-amz-security-token", session_token.clone()));
+    }
+    let (canonical_request, signed_headers) =
+        aws_canonical_request(method, uri, &config.service, &headers, &payload_hash);
+
+    let scope = format!("{date}/{}/{}/aws4_request", config.region, config.service);
+    let signature = aws_signature(config, &amz_date, &canonical_request);
+
+    request_builder = request_builder
+        .header("x-amz-date", amz_date)
+        .header("x-amz-content-sha256", payload_hash)
+        .header(
+            "Authorization",
+            format!(
+                "AWS4-HMAC-SHA256 Credential={}/{scope}, SignedHeaders={signed_headers}, Signature={signature}",
+                config.access_key_id
+            ),
+        );
+    if let Some(session_token) = &config.session_token {
+        request_builder = request_builder.header("x-amz-security-token", session_token);
+    }
+
+    Ok(request_builder)
+}
+
+async fn sign_hmac(
+    mut request_builder: Builder,
+    config: &HmacSigning,
+    method: &str,
+    uri: &Uri,
+    host: Option<String>,
+    body: SignedBody<'_>,
+) -> Result<Builder> {
+    let now = chrono::Utc::now();
+    let timestamp = match config.timestamp_format {
+        TimestampFormat::UnixSeconds => now.timestamp().to_string(),
+        TimestampFormat::UnixMillis => now.timestamp_millis().to_string(),
+        TimestampFormat::Rfc3339 => now.to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+    };
+    let host = match host {
+        Some(host) => host,
+        None => host_header(uri)?,
+    };
+    let path_and_query = uri
+        .path_and_query()
+        .map(|path_and_query| path_and_query.as_str())
+        .unwrap_or("/");
+
+    // The body is only read when it's part of the signature.
+    let template = &config.string_to_sign;
+    let mut string_to_sign = template
+        .replace("{method}", method)
+        .replace("{host}", &host)
+        .replace("{path_and_query}", path_and_query)
+        .replace("{path}", uri.path())
+        .replace("{query}", uri.query().unwrap_or_default())
+        .replace("{timestamp}", &timestamp);
+    if template.contains("{body_sha256}") {
+        string_to_sign = string_to_sign.replace("{body_sha256}", &body.sha256().await?);
+    }
+    if template.contains("{body}") {
+        // A file is streamed as it's sent, so it's never read into memory to
+        // be signed.
+        let SignedBody::Bytes(bytes) = body else {
+            return Err(
+                "{body} can't be signed with a BinaryOctetFilePath body, use {body_sha256}".into(),
+            );
+        };
+        string_to_sign = string_to_sign.replace("{body}", &String::from_utf8_lossy(bytes));
+    }
+
+    let signature = match config.algorithm {
+        HmacAlgorithm::Sha256 => hmac_sha256(config.secret.as_bytes(), string_to_sign.as_bytes()),
+        HmacAlgorithm::Sha512 => hmac_sha512(config.secret.as_bytes(), string_to_sign.as_bytes()),
+    };
+    let signature = match config.encoding {
+        SignatureEncoding::Hex => hex::encode(signature),
+        SignatureEncoding::Base64 => base64::engine::general_purpose::STANDARD.encode(signature),
+    };
+
+    request_builder = request_builder.header(
+        &config.header,
+        config.header_value.replace("{signature}", &signature),
+    );
+    if let Some(timestamp_header) = &config.timestamp_header {
+        request_builder = request_builder.header(timestamp_header, timestamp);
+    }
+
+    Ok(request_builder)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The credentials and requests of the AWS Signature Version 4 test suite.
+    fn test_suite_config() -> AwsSigV4 {
+        AwsSigV4 {
+            access_key_id: "AKIDEXAMPLE".into(),
+            secret_access_key: "wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY".into(),
+            session_token: None,
+            region: "us-east-1".into(),
+            service: "service".into(),
+        }
+    }
+
+    const EMPTY_SHA256: &str = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+
+    fn test_suite_signature(method: &str, url: &str) -> (String, String) {
+        let uri: Uri = url.parse().unwrap();
+        let headers = [
+            ("host", "example.amazonaws.com".to_string()),
+            ("x-amz-date", "20150830T123600Z".to_string()),
+        ];
+        let (canonical_request, _) =
+            aws_canonical_request(method, &uri, "service", &headers, EMPTY_SHA256);
+        let signature = aws_signature(&test_suite_config(), "20150830T123600Z", &canonical_request);
+        (canonical_request, signature)
+    }
+
+    #[test]
+    fn aws_sigv4_get_vanilla() {
+        let (canonical_request, signature) =
+            test_suite_signature("GET", "https://example.amazonaws.com/");
+        assert_eq!(
+            canonical_request,
+            format!(
+                "GET\n/\n\nhost:example.amazonaws.com\nx-amz-date:20150830T123600Z\n\nhost;x-amz-date\n{EMPTY_SHA256}"
+            )
+        );
+        assert_eq!(
+            signature,
+            "5fa00fa31553b73ebf1942676e86291e8372ff2a2260956d9b8aae1d763fbf31"
+        );
+    }
+
+    #[test]
+    fn aws_sigv4_get_vanilla_query_order_key_case() {
+        let (_, signature) = test_suite_signature(
+            "GET",
+            "https://example.amazonaws.com/?Param2=value2&Param1=value1",
+        );
+        assert_eq!(
+            signature,
+            "b97d918cfa904a5beff61c982a1b6f458b799221646efd99d3219ec94cdf2500"
+        );
+    }
+
+    #[test]
+    fn aws_sigv4_post_vanilla() {
+        let (_, signature) = test_suite_signature("POST", "https://example.amazonaws.com/");
+        assert_eq!(
+            signature,
+            "5da7c1a2acd57cee7505fc6676e4e544621c30862966e37dddb68e92efbe5d6b"
+        );
+    }
+
+    #[test]
+    fn aws_sigv4_signing_key() {
+        // The example of deriving a signing key in the AWS documentation.
+        let config = AwsSigV4 {
+            region: "us-east-1".into(),
+            service: "iam".into(),
+            ..test_suite_config()
+        };
+        assert_eq!(
+            hex::encode(aws_signing_key(&config, "20120215")),
+            "f4780e2d9f65fa895f9c67b32ce1baf0b0d8a43505a000a1a9e090d414db404d"
+        );
+    }
+
+    #[tokio::test]
+    async fn file_body_hash_matches_bytes() {
+        let path = std::env::temp_dir().join("lorust_signed_body_test");
+        let content = vec![7u8; 200 * 1024];
+        std::fs::write(&path, &content).unwrap();
+
+        let file_hash = SignedBody::File(path.to_str().unwrap())
+            .sha256()
+            .await
+            .unwrap();
+        let bytes_hash = SignedBody::Bytes(&content).sha256().await.unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(file_hash, bytes_hash);
+    }
+}
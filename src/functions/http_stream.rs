@@ -0,0 +1,228 @@
+use futures::io::AsyncReadExt;
+use isahc::AsyncBody;
+use rhai::{Array, Dynamic, Map};
+use serde::{Deserialize, Serialize};
+use tokio::time::Instant;
+
+use super::result::*;
+
+/// How the events are separated in the response body.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy)]
+pub enum StreamFormat {
+    /// Server-sent events, separated by a blank line.
+    #[default]
+    Sse,
+
+    /// Every non-empty line is an event, as in newline delimited json.
+    Lines,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct EventStreamConfig {
+    #[serde(default)]
+    pub format: StreamFormat,
+
+    /// Stop reading after this many events.
+    #[serde(default)]
+    pub max_events: Option<usize>,
+
+    /// Rhai expression that stops reading when it's true for an event, which
+    /// is available as `event`.
+    #[serde(default)]
+    pub stop_when: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct StreamMetric {
+    /// Time from the start of the request until the first byte of the body.
+    pub time_to_first_byte: Option<u128>,
+
+    /// Time from the start of the request until the first complete event.
+    pub time_to_first_event: Option<u128>,
+
+    pub event_count: usize,
+
+    /// Time from the start of the request until each event was complete.
+    pub event_times: Vec<u128>,
+
+    /// Whether the stream was closed because of `max_events` or `stop_when`
+    /// before the server ended it.
+    pub stopped_early: bool,
+}
+
+/// Builds the events out of the lines of the body.
+#[derive(Default)]
+struct EventParser {
+    id: String,
+    event: String,
+    data: Vec<String>,
+}
+
+impl EventParser {
+    /// Returns the event that the line completes, if any.
+    fn push_line(&mut self, format: StreamFormat, line: &str) -> Option<Map> {
+        match format {
+            StreamFormat::Lines if line.trim().is_empty() => None,
+            StreamFormat::Lines => Some(event_map("", "message", line.to_string())),
+            StreamFormat::Sse if line.is_empty() => {
+                if self.data.is_empty() {
+                    self.event.clear();
+                    return None;
+                }
+                let event = match self.event.as_str() {
+                    "" => "message",
+                    event => event,
+                };
+                let event = event_map(&self.id, event, self.data.join("\n"));
+                self.event.clear();
+                self.data.clear();
+                Some(event)
+            }
+            StreamFormat::Sse => {
+                let (field, value) = line.split_once(':').unwrap_or((line, ""));
+                let value = value.strip_prefix(' ').unwrap_or(value);
+                match field {
+                    "data" => self.data.push(value.to_string()),
+                    "event" => self.event = value.to_string(),
+                    "id" => self.id = value.to_string(),
+                    // Comments and retry are ignored.
+                    _ => {}
+                }
+                None
+            }
+        }
+    }
+}
+
+fn event_map(id: &str, event: &str, data: String) -> Map {
+    let mut map = Map::new();
+    map.insert("id".into(), Dynamic::from(id.to_string()));
+    map.insert("event".into(), Dynamic::from(event.to_string()));
+    map.insert("data".into(), Dynamic::from(data));
+    map
+}
+
+/// Evaluates the `stop_when` condition of the stream.
+pub struct StopCondition {
+    engine: rhai::Engine,
+    ast: rhai::AST,
+}
+
+impl StopCondition {
+    pub fn new(expression: &str) -> Result<Self> {
+        let engine = rhai::Engine::new();
+        let ast = engine.compile_expression(expression)?;
+        Ok(StopCondition { engine, ast })
+    }
+
+    fn is_met(&self, event: &Map) -> std::io::Result<bool> {
+        let mut scope = rhai::Scope::new();
+        scope.push_constant("event", event.clone());
+        self.engine
+            .eval_ast_with_scope::<bool>(&mut scope, &self.ast)
+            .map_err(|err| std::io::Error::other(format!("stop_when failed: {err}")))
+    }
+}
+
+/// The events read from the stream along with their timing.
+pub struct EventStream {
+    pub body_size: usize,
+    pub captured: Vec<u8>,
+    pub events: Array,
+    pub metric: StreamMetric,
+}
+
+fn millis(started_at: Instant) -> u128 {
+    started_at.elapsed().as_millis()
+}
+
+/// Reads the body as it arrives, splitting it into events until the server
+/// ends the stream or one of the stop conditions is met.
+pub async fn read_event_stream(
+    body: &mut AsyncBody,
+    config: &EventStreamConfig,
+    stop_condition: Option<&StopCondition>,
+    max_capture_size: Option<usize>,
+    started_at: Instant,
+) -> std::io::Result<EventStream> {
+    let max_capture_size = max_capture_size.unwrap_or(usize::MAX);
+    let mut parser = EventParser::default();
+    let mut stream = EventStream {
+        body_size: 0,
+        captured: Vec::new(),
+        events: Array::new(),
+        metric: StreamMetric {
+            time_to_first_byte: None,
+            time_to_first_event: None,
+            event_count: 0,
+            event_times: Vec::new(),
+            stopped_early: false,
+        },
+    };
+
+    let mut pending = Vec::new();
+    let mut buf = vec![0; 16 * 1024];
+    let mut ended = false;
+    'read: while !ended {
+        let len = body.read(&mut buf).await?;
+        if len == 0 {
+            // The last line may not end with a newline.
+            if pending.is_empty() {
+                break;
+            }
+            pending.push(b'\n');
+            ended = true;
+        } else {
+            if stream.metric.time_to_first_byte.is_none() {
+                stream.metric.time_to_first_byte = Some(millis(started_at));
+            }
+            stream.body_size += len;
+
+            let capture_len = len.min(max_capture_size - stream.captured.len());
+            stream.captured.extend_from_slice(&buf[..capture_len]);
+            pending.extend_from_slice(&buf[..len]);
+        }
+
+        // Lines can end with \n, \r\n or \r. A trailing \r is kept until the
+        // next read, as it may be followed by \n.
+        while let Some(end) = pending
+            .iter()
+            .position(|&byte| byte == b'\n' || byte == b'\r')
+        {
+            if pending[end] == b'\r' && end + 1 == pending.len() {
+                break;
+            }
+            let separator_len = match &pending[end..] {
+                [b'\r', b'\n', ..] => 2,
+                _ => 1,
+            };
+            let line = String::from_utf8_lossy(&pending[..end]).into_owned();
+            pending.drain(..end + separator_len);
+
+            let Some(mut event) = parser.push_line(config.format, &line) else {
+                continue;
+            };
+
+            let time = millis(started_at);
+            event.insert("time".into(), Dynamic::from_int(time as i64));
+            if stream.metric.time_to_first_event.is_none() {
+                stream.metric.time_to_first_event = Some(time);
+            }
+            stream.metric.event_count += 1;
+            stream.metric.event_times.push(time);
+
+            let stop = match stop_condition {
+                Some(condition) => condition.is_met(&event)?,
+                None => false,
+            };
+            stream.events.push(Dynamic::from_map(event));
+
+            if stop || Some(stream.metric.event_count) == config.max_events {
+                stream.metric.stopped_early = true;
+                break 'read;
+            }
+        }
+    }
+
+    Ok(stream)
+}
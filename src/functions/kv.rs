@@ -0,0 +1,88 @@
+use rhai::Dynamic;
+use serde::{Deserialize, Serialize};
+use tokio::sync::oneshot;
+
+use crate::kv_store::commands::{Command, Sender, Value};
+
+use super::result::*;
+
+/// Determines which requests share the same connection pool. The HTTP cookies
+/// are always kept per virtual user regardless of the policy.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq)]
+pub enum ConnectionPolicy {
+    /// All the virtual users share a single connection pool.
+    Shared,
+
+    /// Each virtual user has its own connection pool for every session.
+    #[default]
+    PerUser,
+
+    /// A new connection is opened for every request.
+    PerRequest,
+}
+
+/// Name of the key in the kv store that holds the value of `name` for the
+/// given session or connection.
+pub fn named_key(name: &str, session: Option<&str>) -> String {
+    match session {
+        Some(session) => format!("{name}:{session}"),
+        None => name.to_string(),
+    }
+}
+
+pub async fn get_value(kv_tx: &Sender, key: &str) -> Result<Dynamic> {
+    let (resp_tx, resp_rx) = oneshot::channel();
+    kv_tx
+        .send(Command::Get {
+            key: key.to_string(),
+            resp: resp_tx,
+        })
+        .await?;
+    let value = match resp_rx.await?? {
+        Value::Dynamic(value) => value,
+        Value::Array(value) => Dynamic::from_array(value),
+    };
+    Ok(value)
+}
+
+pub async fn set_value(kv_tx: &Sender, key: &str, value: Dynamic) -> Result<()> {
+    let (resp_tx, resp_rx) = oneshot::channel();
+    kv_tx
+        .send(Command::Set {
+            key: key.to_string(),
+            value,
+            resp: resp_tx,
+        })
+        .await?;
+    resp_rx.await??;
+    Ok(())
+}
+
+/// Returns the value of the key, storing `value` first if there is none.
+pub async fn get_or_set_value(kv_tx: &Sender, key: &str, value: Dynamic) -> Result<Dynamic> {
+    let (resp_tx, resp_rx) = oneshot::channel();
+    kv_tx
+        .send(Command::GetOrSet {
+            key: key.to_string(),
+            value,
+            resp: resp_tx,
+        })
+        .await?;
+    let value = match resp_rx.await?? {
+        Value::Dynamic(value) => value,
+        Value::Array(value) => Dynamic::from_array(value),
+    };
+    Ok(value)
+}
+
+pub async fn delete_value(kv_tx: &Sender, key: &str) -> Result<()> {
+    let (resp_tx, resp_rx) = oneshot::channel();
+    kv_tx
+        .send(Command::Delete {
+            key: key.to_string(),
+            resp: resp_tx,
+        })
+        .await?;
+    resp_rx.await??;
+    Ok(())
+}
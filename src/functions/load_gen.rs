@@ -5,7 +5,7 @@ use serde::{Deserialize, Serialize};
 
 use crate::{
     flow::Function,
-    functions::{http_request::HttpMetric, run::run_functions},
+    functions::{metrics::Metric, rhai_code::eval_rhai_code, run::run_functions},
     kv_store::commands::{Command, Sender, Value},
 };
 
@@ -15,6 +15,7 @@ use tokio::{
 };
 
 use super::result::*;
+use super::sleep::DurationValue;
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct LoadGenParam {
@@ -25,9 +26,64 @@ pub struct LoadGenParam {
     #[serde(default)]
     max_tasks: Option<u64>,
 
+    /// Repeats the functions of each virtual user until the timeout, starting
+    /// an iteration at most once per interval.
+    #[serde(default)]
+    pacing: Option<Pacing>,
+
     functions_to_execute: Vec<Function>,
 }
 
+/// Outcome of an iteration of a virtual user, recorded when the iterations are
+/// paced.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct IterationMetric {
+    /// Index of the virtual user, counting from 0.
+    pub vu_index: u64,
+
+    /// Index of the iteration, counting from 0.
+    pub iteration: i64,
+
+    /// When did the iteration start
+    pub time_stamp: String,
+
+    /// Time taken by the functions of the iteration.
+    pub elapsed_time: u128,
+
+    pub success: bool,
+}
+
+/// Interval between the starts of the iterations of a virtual user.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum Pacing {
+    Interval(DurationValue),
+
+    /// A Rhai expression that returns the interval, as a number of seconds or
+    /// as a duration such as `"500ms"`. It's evaluated after each iteration,
+    /// with the variables of the virtual user as well as `iteration`, which
+    /// counts from 0, and `iteration_time`, the seconds the iteration took.
+    Expression(String),
+}
+
+impl Pacing {
+    pub async fn interval(&self, local_kv_tx: &Sender) -> Result<Duration> {
+        let interval = match self {
+            Pacing::Interval(interval) => interval.clone(),
+            Pacing::Expression(expression) => {
+                let value = eval_rhai_code(expression, local_kv_tx.clone()).await?;
+                if let Ok(seconds) = value.as_float() {
+                    DurationValue::Seconds(seconds)
+                } else if let Ok(seconds) = value.as_int() {
+                    DurationValue::Seconds(seconds as f64)
+                } else {
+                    DurationValue::Text(value.to_string())
+                }
+            }
+        };
+        interval.to_duration()
+    }
+}
+
 fn max(a: i64, b: i64) -> i64 {
     if a > b {
         a
@@ -96,6 +152,8 @@ pub async fn load_gen(param: LoadGenParam, kv_tx: Sender) -> FunctionResult {
             param.functions_to_execute.clone(),
             kv_tx.clone(),
             param.timeout,
+            param.pacing.clone(),
+            i,
         )));
 
         let spawn_rate = eval_task_count(&param.spawn_rate, tick)?.max(1) as u64;
@@ -146,9 +204,9 @@ pub async fn load_gen(param: LoadGenParam, kv_tx: Sender) -> FunctionResult {
 
     if let Value::Array(mut metrics) = metrics {
         println!("Collected metrics array size: {:?}", metrics.len());
-        let metrics: Vec<HttpMetric> = metrics
+        let metrics: Vec<Metric> = metrics
             .iter_mut()
-            .map(|x| x.take().cast::<HttpMetric>())
+            .map(|x| x.take().cast::<Metric>())
             .collect();
 
         let json_str = serde_json::to_string(&metrics)?;
@@ -0,0 +1,119 @@
+use rhai::Dynamic;
+use serde::{Deserialize, Serialize};
+use tokio::sync::oneshot;
+
+use crate::kv_store::commands::{Command, Sender};
+
+use super::exec::ExecMetric;
+use super::grpc::GrpcMetric;
+use super::http_request::HttpMetric;
+use super::load_gen::IterationMetric;
+use super::redis::RedisMetric;
+use super::rendezvous::RendezvousMetric;
+use super::result::*;
+use super::socket::SocketMetric;
+use super::sql::SqlMetric;
+use super::websocket::WebSocketMetric;
+
+/// A metric of any of the functions. All of them are collected in the same
+/// array, each serialized with its own fields and a `type` field that tells
+/// which kind of metric it is.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type")]
+pub enum Metric {
+    Http(Box<HttpMetric>),
+    WebSocket(WebSocketMetric),
+    Grpc(GrpcMetric),
+    Socket(SocketMetric),
+    Sql(SqlMetric),
+    Redis(RedisMetric),
+    Exec(ExecMetric),
+    Rendezvous(RendezvousMetric),
+    Iteration(IterationMetric),
+}
+
+impl From<HttpMetric> for Metric {
+    fn from(metric: HttpMetric) -> Self {
+        Metric::Http(Box::new(metric))
+    }
+}
+
+impl From<WebSocketMetric> for Metric {
+    fn from(metric: WebSocketMetric) -> Self {
+        Metric::WebSocket(metric)
+    }
+}
+
+impl From<GrpcMetric> for Metric {
+    fn from(metric: GrpcMetric) -> Self {
+        Metric::Grpc(metric)
+    }
+}
+
+impl From<SocketMetric> for Metric {
+    fn from(metric: SocketMetric) -> Self {
+        Metric::Socket(metric)
+    }
+}
+
+impl From<SqlMetric> for Metric {
+    fn from(metric: SqlMetric) -> Self {
+        Metric::Sql(metric)
+    }
+}
+
+impl From<RedisMetric> for Metric {
+    fn from(metric: RedisMetric) -> Self {
+        Metric::Redis(metric)
+    }
+}
+
+impl From<ExecMetric> for Metric {
+    fn from(metric: ExecMetric) -> Self {
+        Metric::Exec(metric)
+    }
+}
+
+impl From<RendezvousMetric> for Metric {
+    fn from(metric: RendezvousMetric) -> Self {
+        Metric::Rendezvous(metric)
+    }
+}
+
+impl From<IterationMetric> for Metric {
+    fn from(metric: IterationMetric) -> Self {
+        Metric::Iteration(metric)
+    }
+}
+
+/// Metrics are only collected while a load generator is running.
+pub async fn should_collect_metrics(global_kv_tx: &Sender) -> Result<bool> {
+    let (resp_tx, resp_rx) = oneshot::channel();
+    global_kv_tx
+        .send(Command::Exists {
+            key: "load_gen_metrics".into(),
+            resp: resp_tx,
+        })
+        .await?;
+    resp_rx.await?
+}
+
+pub async fn append_metric(global_kv_tx: &Sender, metric: impl Into<Metric>) -> Result<()> {
+    let (resp_tx, resp_rx) = oneshot::channel();
+    global_kv_tx
+        .send(Command::Append {
+            key: "load_gen_metrics".into(),
+            value: Dynamic::from(metric.into()),
+            resp: resp_tx,
+        })
+        .await?;
+    resp_rx.await??;
+    Ok(())
+}
+
+/// Time stamp of when a request or an operation started.
+pub fn time_stamp() -> String {
+    chrono::Local::now()
+        .format("%Y-%m-%d %H:%M:%S.%f")
+        .to_string()
+}
@@ -1,6 +1,22 @@
+pub mod exec;
+pub mod graphql;
+pub mod grpc;
+pub mod http_auth;
+pub mod http_multipart;
 pub mod http_request;
+pub mod http_session;
+pub mod http_signing;
+pub mod http_stream;
+pub mod kv;
 pub mod load_gen;
+pub mod metrics;
+pub mod redis;
+pub mod rendezvous;
 pub mod result;
 pub mod rhai_code;
 pub mod run;
 pub mod sleep;
+pub mod socket;
+pub mod sql;
+pub mod timeout;
+pub mod websocket;
@@ -0,0 +1,352 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use redis::aio::MultiplexedConnection;
+use redis::{AsyncConnectionConfig, RedisError, Value};
+use rhai::{Dynamic, Map};
+use serde::{Deserialize, Serialize};
+use tokio::sync::OnceCell;
+use tokio::time::Instant;
+
+use crate::kv_store::commands::Sender;
+
+use super::kv::{delete_value, get_or_set_value, named_key, set_value, ConnectionPolicy};
+use super::metrics::{append_metric, should_collect_metrics, time_stamp};
+use super::result::*;
+use super::timeout::function_timeout;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RedisMetric {
+    /// Name of the connection, empty for the default one.
+    pub connection: String,
+
+    /// Name of the command, without its arguments.
+    pub command: String,
+
+    /// When did the command start
+    pub time_stamp: String,
+
+    /// Time taken by the command, including the time to connect when there
+    /// was no open connection.
+    pub elapsed_time: u128,
+
+    pub success: bool,
+
+    /// Why the command failed, if it did.
+    pub error: String,
+
+    pub tag: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RedisParam {
+    /// Name of the connection, the default connection is used when not set.
+    #[serde(default)]
+    pub connection: Option<String>,
+
+    /// Server to connect to, such as `redis://localhost:6379/0`. Only needed
+    /// by the first command of a connection.
+    #[serde(default)]
+    pub url: Option<String>,
+
+    /// The command and its arguments, such as `["SET", "key", "value"]`.
+    pub command: Vec<String>,
+
+    #[serde(default)]
+    pub connection_policy: ConnectionPolicy,
+
+    #[serde(default)]
+    pub timeout: Option<u64>,
+
+    #[serde(default)]
+    pub tag: Option<String>,
+}
+
+/// Converts the reply into the closest Rhai value. Bulk strings that aren't
+/// valid UTF-8 are kept as blobs.
+fn reply_to_dynamic(value: Value) -> Dynamic {
+    match value {
+        Value::Nil => Dynamic::UNIT,
+        Value::Int(value) => Dynamic::from_int(value),
+        Value::BulkString(bytes) => match String::from_utf8(bytes) {
+            Ok(text) => Dynamic::from(text),
+            Err(err) => Dynamic::from_blob(err.into_bytes()),
+        },
+        Value::Array(values) | Value::Set(values) | Value::Push { data: values, .. } => {
+            Dynamic::from_array(values.into_iter().map(reply_to_dynamic).collect())
+        }
+        Value::SimpleString(text) => Dynamic::from(text),
+        Value::Okay => Dynamic::from("OK".to_string()),
+        Value::Map(entries) => {
+            let mut map = Map::new();
+            for (key, value) in entries {
+                let key = reply_to_dynamic(key).to_string();
+                map.insert(key.into(), reply_to_dynamic(value));
+            }
+            Dynamic::from_map(map)
+        }
+        Value::Attribute { data, .. } => reply_to_dynamic(*data),
+        Value::Double(value) => Dynamic::from_float(value),
+        Value::Boolean(value) => Dynamic::from_bool(value),
+        Value::VerbatimString { text, .. } => Dynamic::from(text),
+        Value::BigNumber(value) => Dynamic::from(value.to_string()),
+        Value::ServerError(err) => Dynamic::from(format!("{err:?}")),
+    }
+}
+
+/// Connection that's opened by the first command that needs it, while the
+/// other commands wait for it.
+#[derive(Clone, Default)]
+struct LazyConnection(Arc<OnceCell<MultiplexedConnection>>);
+
+async fn open_connection(
+    param: &RedisParam,
+    timeout: Duration,
+) -> std::result::Result<MultiplexedConnection, String> {
+    let url = param.url.as_deref().ok_or_else(|| {
+        format!(
+            "redis connection '{}' has no url",
+            param.connection.as_deref().unwrap_or_default()
+        )
+    })?;
+    let config = AsyncConnectionConfig::new()
+        .set_connection_timeout(timeout)
+        .set_response_timeout(timeout);
+    redis::Client::open(url)
+        .map_err(|err| format!("Invalid url: {err}"))?
+        .get_multiplexed_async_connection_with_config(&config)
+        .await
+        .map_err(|err| format!("Connection failed: {err}"))
+}
+
+/// Returns the connection that matches the connection policy, opening and
+/// storing a new one if there is none yet. A shared connection multiplexes
+/// the commands of all the virtual users, and is only opened once even when
+/// they all start at the same time.
+async fn get_connection(
+    param: &RedisParam,
+    timeout: Duration,
+    global_kv_tx: &Sender,
+    local_kv_tx: &Sender,
+) -> std::result::Result<MultiplexedConnection, String> {
+    let kv_tx = match param.connection_policy {
+        ConnectionPolicy::PerRequest => return open_connection(param, timeout).await,
+        ConnectionPolicy::Shared => global_kv_tx,
+        ConnectionPolicy::PerUser => local_kv_tx,
+    };
+
+    let key = named_key("redis_connection", param.connection.as_deref());
+    let connection = get_or_set_value(kv_tx, &key, Dynamic::from(LazyConnection::default()))
+        .await
+        .map_err(|err| err.to_string())?
+        .try_cast::<LazyConnection>()
+        .ok_or("the stored redis connection has an unexpected type")?;
+    let connection = connection
+        .0
+        .get_or_try_init(|| open_connection(param, timeout))
+        .await?;
+    Ok(connection.clone())
+}
+
+/// A connection that failed is dropped, so that the next command reconnects.
+fn is_connection_error(err: &RedisError) -> bool {
+    err.is_io_error() || err.is_connection_dropped() || err.is_timeout()
+}
+
+/// Sends the command and stores the reply in the `redis_reply` variable, or
+/// the error in `redis_error`, which is empty unless the command failed.
+pub async fn send_command(
+    param: RedisParam,
+    timeout: Option<Duration>,
+    global_kv_tx: Sender,
+    local_kv_tx: Sender,
+) -> FunctionResult {
+    let timeout = function_timeout(param.timeout, timeout);
+
+    let (name, args) = param
+        .command
+        .split_first()
+        .ok_or("the redis command is empty")?;
+    let mut command = redis::cmd(name);
+    for arg in args {
+        command.arg(arg);
+    }
+
+    let time_stamp = time_stamp();
+    let started_at = Instant::now();
+    let result = match get_connection(&param, timeout, &global_kv_tx, &local_kv_tx).await {
+        Ok(mut connection) => match command.query_async::<Value>(&mut connection).await {
+            Ok(reply) => Ok(reply),
+            Err(err) => {
+                if is_connection_error(&err) {
+                    let key = named_key("redis_connection", param.connection.as_deref());
+                    match param.connection_policy {
+                        ConnectionPolicy::Shared => delete_value(&global_kv_tx, &key).await?,
+                        ConnectionPolicy::PerUser => delete_value(&local_kv_tx, &key).await?,
+                        ConnectionPolicy::PerRequest => {}
+                    }
+                }
+                Err(format!("Command failed: {err}"))
+            }
+        },
+        Err(err) => Err(err),
+    };
+    let elapsed_time = started_at.elapsed();
+
+    let (reply, error) = match result {
+        Ok(reply) => (reply_to_dynamic(reply), None),
+        Err(err) => (Dynamic::UNIT, Some(err)),
+    };
+    set_value(&local_kv_tx, "redis_reply", reply).await?;
+    set_value(
+        &local_kv_tx,
+        "redis_error",
+        Dynamic::from(error.clone().unwrap_or_default()),
+    )
+    .await?;
+
+    if should_collect_metrics(&global_kv_tx).await? {
+        let metric = RedisMetric {
+            connection: param.connection.unwrap_or_default(),
+            command: name.to_uppercase(),
+            time_stamp,
+            elapsed_time: elapsed_time.as_millis(),
+            success: error.is_none(),
+            error: error.clone().unwrap_or_default(),
+            tag: param.tag.unwrap_or_default(),
+        };
+        append_metric(&global_kv_tx, metric).await?;
+    }
+
+    match error {
+        Some(_) => Ok(FunctionStatus::Failed),
+        None => Ok(FunctionStatus::Passed),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::{TcpListener, TcpStream};
+
+    use super::*;
+    use crate::functions::kv::get_value;
+    use crate::kv_store::store::new as kv_store_new;
+
+    /// Reads a command sent as an array of bulk strings.
+    async fn read_command(reader: &mut BufReader<TcpStream>) -> Option<Vec<String>> {
+        let mut line = String::new();
+        reader.read_line(&mut line).await.ok()?;
+        let count: usize = line.trim_end().strip_prefix('*')?.parse().ok()?;
+        let mut args = Vec::new();
+        for _ in 0..count {
+            line.clear();
+            reader.read_line(&mut line).await.ok()?;
+            let len: usize = line.trim_end().strip_prefix('$')?.parse().ok()?;
+            let mut arg = vec![0; len + 2];
+            reader.read_exact(&mut arg).await.ok()?;
+            arg.truncate(len);
+            args.push(String::from_utf8(arg).ok()?);
+        }
+        Some(args)
+    }
+
+    /// Answers `SET` with `+OK`, `GET` with `bar` and anything else with an
+    /// error, counting the connections it accepts.
+    async fn start_server() -> (String, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("redis://{}", listener.local_addr().unwrap());
+        let connections = Arc::new(AtomicUsize::new(0));
+        let accepted = connections.clone();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                accepted.fetch_add(1, Ordering::SeqCst);
+                tokio::spawn(async move {
+                    let mut reader = BufReader::new(stream);
+                    while let Some(args) = read_command(&mut reader).await {
+                        let reply: &[u8] = match args[0].to_uppercase().as_str() {
+                            "SET" | "CLIENT" => b"+OK\r\n",
+                            "GET" => b"$3\r\nbar\r\n",
+                            _ => b"-ERR unknown command\r\n",
+                        };
+                        reader.get_mut().write_all(reply).await.unwrap();
+                    }
+                });
+            }
+        });
+        (url, connections)
+    }
+
+    fn redis_param(url: &str, command: &[&str], policy: ConnectionPolicy) -> RedisParam {
+        RedisParam {
+            connection: None,
+            url: Some(url.into()),
+            command: command.iter().map(|arg| arg.to_string()).collect(),
+            connection_policy: policy,
+            timeout: Some(5),
+            tag: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn commands_against_a_resp_server() {
+        let (url, _connections) = start_server().await;
+        let (_global_kv_handle, global_kv_tx) = kv_store_new().await;
+        let (_local_kv_handle, local_kv_tx) = kv_store_new().await;
+
+        let param = redis_param(&url, &["SET", "foo", "bar"], ConnectionPolicy::PerUser);
+        let status = send_command(param, None, global_kv_tx.clone(), local_kv_tx.clone())
+            .await
+            .unwrap();
+        assert!(matches!(status, FunctionStatus::Passed));
+        let reply = get_value(&local_kv_tx, "redis_reply").await.unwrap();
+        assert_eq!(reply.cast::<String>(), "OK");
+
+        let param = redis_param(&url, &["GET", "foo"], ConnectionPolicy::PerUser);
+        let status = send_command(param, None, global_kv_tx.clone(), local_kv_tx.clone())
+            .await
+            .unwrap();
+        assert!(matches!(status, FunctionStatus::Passed));
+        let reply = get_value(&local_kv_tx, "redis_reply").await.unwrap();
+        assert_eq!(reply.cast::<String>(), "bar");
+        let error = get_value(&local_kv_tx, "redis_error").await.unwrap();
+        assert_eq!(error.cast::<String>(), "");
+
+        let param = redis_param(&url, &["NOPE"], ConnectionPolicy::PerUser);
+        let status = send_command(param, None, global_kv_tx, local_kv_tx.clone())
+            .await
+            .unwrap();
+        assert!(matches!(status, FunctionStatus::Failed));
+        assert!(get_value(&local_kv_tx, "redis_reply")
+            .await
+            .unwrap()
+            .is_unit());
+        let error = get_value(&local_kv_tx, "redis_error").await.unwrap();
+        assert!(error.cast::<String>().contains("unknown command"));
+    }
+
+    #[tokio::test]
+    async fn shared_connection_is_opened_once() {
+        let (url, connections) = start_server().await;
+        let (_global_kv_handle, global_kv_tx) = kv_store_new().await;
+
+        let mut tasks = Vec::new();
+        for _ in 0..20 {
+            let param = redis_param(&url, &["GET", "foo"], ConnectionPolicy::Shared);
+            let global_kv_tx = global_kv_tx.clone();
+            tasks.push(tokio::spawn(async move {
+                let (_local_kv_handle, local_kv_tx) = kv_store_new().await;
+                send_command(param, None, global_kv_tx, local_kv_tx)
+                    .await
+                    .unwrap()
+            }));
+        }
+        for task in tasks {
+            assert!(matches!(task.await.unwrap(), FunctionStatus::Passed));
+        }
+        assert_eq!(connections.load(Ordering::SeqCst), 1);
+    }
+}
@@ -0,0 +1,170 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use rhai::Dynamic;
+use serde::{Deserialize, Serialize};
+use tokio::sync::{oneshot, watch, Mutex};
+use tokio::time::Instant;
+
+use crate::kv_store::commands::{Command, Sender, Value};
+
+use super::metrics::{append_metric, should_collect_metrics, time_stamp};
+use super::result::*;
+use super::timeout::function_timeout;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RendezvousMetric {
+    pub name: String,
+
+    /// When did the virtual user arrive
+    pub time_stamp: String,
+
+    /// Time the virtual user waited until it was released.
+    pub wait_time: u128,
+
+    /// Number of virtual users that were released together.
+    pub released_users: usize,
+
+    /// Whether the users were released by the timeout instead of the count.
+    pub timed_out: bool,
+
+    pub tag: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RendezvousParam {
+    /// Virtual users wait for the others that reached a rendezvous with the
+    /// same name.
+    pub name: String,
+
+    /// Number of virtual users that are released together.
+    pub count: usize,
+
+    /// Seconds to wait for the others, after which all the waiting users are
+    /// released.
+    #[serde(default)]
+    pub timeout: Option<u64>,
+
+    #[serde(default)]
+    pub tag: Option<String>,
+}
+
+#[derive(Debug, Clone, Default)]
+struct Release {
+    generation: u64,
+    users: usize,
+    timed_out: bool,
+}
+
+#[derive(Debug, Default)]
+struct Waiting {
+    users: usize,
+    generation: u64,
+}
+
+/// A barrier that releases the waiting users together, then starts over with
+/// the next generation of users.
+#[derive(Debug)]
+struct BarrierInner {
+    waiting: Mutex<Waiting>,
+    released: watch::Sender<Release>,
+}
+
+#[derive(Debug, Clone)]
+struct Barrier(Arc<BarrierInner>);
+
+impl Barrier {
+    fn new() -> Self {
+        Barrier(Arc::new(BarrierInner {
+            waiting: Mutex::new(Waiting::default()),
+            released: watch::Sender::new(Release::default()),
+        }))
+    }
+
+    fn release(&self, waiting: &mut Waiting, timed_out: bool) -> Release {
+        waiting.generation += 1;
+        let release = Release {
+            generation: waiting.generation,
+            users: std::mem::take(&mut waiting.users),
+            timed_out,
+        };
+        self.0.released.send_replace(release.clone());
+        release
+    }
+
+    /// Waits until `count` users have arrived, or releases the ones that are
+    /// waiting when the timeout expires.
+    async fn arrive(&self, count: usize, timeout: Duration) -> Release {
+        let (generation, mut released) = {
+            let mut waiting = self.0.waiting.lock().await;
+            waiting.users += 1;
+            if waiting.users >= count {
+                return self.release(&mut waiting, false);
+            }
+            (waiting.generation, self.0.released.subscribe())
+        };
+
+        let wait = released.wait_for(|release| release.generation > generation);
+        if let Ok(Ok(release)) = tokio::time::timeout(timeout, wait).await {
+            return release.clone();
+        }
+
+        let mut waiting = self.0.waiting.lock().await;
+        if waiting.generation == generation {
+            self.release(&mut waiting, true)
+        } else {
+            // The others were released while this one was timing out.
+            self.0.released.borrow().clone()
+        }
+    }
+}
+
+/// Returns the barrier of the rendezvous, creating it if this is the first
+/// user to reach it.
+async fn get_barrier(name: &str, global_kv_tx: &Sender) -> Result<Barrier> {
+    let (resp_tx, resp_rx) = oneshot::channel();
+    global_kv_tx
+        .send(Command::GetOrSet {
+            key: format!("rendezvous:{name}"),
+            value: Dynamic::from(Barrier::new()),
+            resp: resp_tx,
+        })
+        .await?;
+    let barrier = match resp_rx.await?? {
+        Value::Dynamic(value) => value.try_cast::<Barrier>(),
+        Value::Array(_) => None,
+    }
+    .ok_or("the rendezvous has an unexpected type")?;
+    Ok(barrier)
+}
+
+/// Waits for the other virtual users to reach the rendezvous, so that they
+/// continue at the same time.
+pub async fn rendezvous(
+    param: RendezvousParam,
+    timeout: Option<Duration>,
+    global_kv_tx: Sender,
+) -> FunctionResult {
+    let timeout = function_timeout(param.timeout, timeout);
+
+    let barrier = get_barrier(&param.name, &global_kv_tx).await?;
+
+    let time_stamp = time_stamp();
+    let started_at = Instant::now();
+    let release = barrier.arrive(param.count, timeout).await;
+    let wait_time = started_at.elapsed();
+
+    if should_collect_metrics(&global_kv_tx).await? {
+        let metric = RendezvousMetric {
+            name: param.name,
+            time_stamp,
+            wait_time: wait_time.as_millis(),
+            released_users: release.users,
+            timed_out: release.timed_out,
+            tag: param.tag.unwrap_or_default(),
+        };
+        append_metric(&global_kv_tx, metric).await?;
+    }
+
+    Ok(FunctionStatus::Passed)
+}
@@ -6,6 +6,7 @@ use tokio::sync::oneshot;
 
 use crate::kv_store::commands::{Command, Sender, Value};
 
+use super::http_session;
 use super::result::*;
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -63,7 +64,7 @@ async fn init_engine_and_scope(
         // If it's a json string, we try to convert it to a rhai::Map,
         // otherwise just store the plain string.
         if value.is::<String>() {
-            value = match engine.parse_json(value.take().cast::<String>(), true) {
+            value = match engine.parse_json(value.clone_cast::<String>(), true) {
                 Ok(map) => Dynamic::from_map(map),
                 _ => value,
             };
@@ -72,6 +73,8 @@ async fn init_engine_and_scope(
     }
     let random = RandomPackage::new();
     random.register_into_engine(&mut engine);
+    let cookie_jars = http_session::get_cookie_jars(local_kv_tx).await?;
+    http_session::register_cookie_functions(&mut engine, cookie_jars);
     Ok((engine, scope))
 }
 
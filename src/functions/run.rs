@@ -2,21 +2,49 @@ use std::borrow::Cow;
 use std::collections::BTreeMap;
 use std::time::Duration;
 
+use async_recursion::async_recursion;
 use regex::Regex;
 
 use rhai::Dynamic;
-use tokio::time::Instant;
+use tokio::sync::oneshot;
+use tokio::time::{sleep_until, Instant};
 
 use crate::flow::{Flow, Function};
-use crate::kv_store::{commands::Sender, store::new as kv_store_new};
+use crate::kv_store::{
+    commands::{Command, Sender},
+    store::new as kv_store_new,
+};
 
-use super::http_request;
-use super::load_gen;
+use super::exec;
+use super::graphql;
+use super::grpc;
+use super::http_request::{self, HttpBody};
+use super::http_session;
+use super::kv::set_value;
+use super::load_gen::{self, IterationMetric};
+use super::metrics::{append_metric, should_collect_metrics, time_stamp};
+use super::redis;
+use super::rendezvous;
 use super::result::*;
 use super::rhai_code;
 use super::sleep;
+use super::socket;
+use super::sql;
+use super::websocket;
 
 pub async fn run_flow(flow: Flow, kv_tx: Sender) -> FunctionResult {
+    if let Some(http_config) = flow.http {
+        let (resp_tx, resp_rx) = oneshot::channel();
+        kv_tx
+            .send(Command::Set {
+                key: "http_config".into(),
+                value: Dynamic::from(http_config),
+                resp: resp_tx,
+            })
+            .await?;
+        resp_rx.await??;
+    }
+
     run_loadgen(flow.functions, kv_tx.clone()).await?;
 
     Ok(FunctionStatus::Passed)
@@ -62,10 +90,63 @@ async fn interpolate_variables(input: &str, local_kv_tx: Sender) -> Result<Cow<'
     Ok(replaced)
 }
 
+/// Json pointers to the values that are interpolated by `interpolate_json_value`
+/// in the serialized functions.
+const TYPED_JSON_VALUES: &[&str] = &[
+    "/HttpRequest/body/Json",
+    "/GraphQL/variables",
+    "/Grpc/request",
+    "/Sql/params",
+];
+
+/// Interpolates the string leaves of a json value in place. A leaf that
+/// consists of a single `%|...|%` expression is replaced with the typed value
+/// of the expression, so numbers, booleans, arrays and maps keep their type.
+#[async_recursion]
+async fn interpolate_json_value(value: &mut serde_json::Value, local_kv_tx: Sender) -> Result<()> {
+    match value {
+        serde_json::Value::String(input) => {
+            let re = Regex::new(r"%\|(.+?)\|%").unwrap();
+            let matches: Vec<_> = re.find_iter(input).collect();
+
+            if let [key] = matches[..] {
+                if key.start() == 0 && key.end() == input.len() {
+                    let key = &input[2..input.len() - 2];
+                    let result = rhai_code::eval_rhai_code(key, local_kv_tx).await?;
+                    *value = rhai::serde::from_dynamic(&result)?;
+                    return Ok(());
+                }
+            }
+
+            if !matches.is_empty() {
+                let replaced = interpolate_variables(input, local_kv_tx)
+                    .await?
+                    .into_owned();
+                *value = serde_json::Value::String(replaced);
+            }
+        }
+        serde_json::Value::Array(items) => {
+            for item in items {
+                interpolate_json_value(item, local_kv_tx.clone()).await?;
+            }
+        }
+        serde_json::Value::Object(map) => {
+            for (_, item) in map.iter_mut() {
+                interpolate_json_value(item, local_kv_tx.clone()).await?;
+            }
+        }
+        _ => {}
+    }
+
+    Ok(())
+}
+
 pub async fn run_functions(
     functions: Vec<Function>,
     global_kv_tx: Sender,
     timeout: u64,
+    pacing: Option<load_gen::Pacing>,
+    vu_index: u64,
 ) -> FunctionResult {
     // TODO: Instead of defining something like this, there should be proper
     // scoping mechanisms with scope names that can be referred from inside
@@ -75,8 +156,108 @@ pub async fn run_functions(
     let end_time = Instant::now() + Duration::from_secs(timeout);
     let mut final_status = FunctionStatus::Passed;
 
+    let vu_index_value = Dynamic::from_int(vu_index as i64);
+    if let Err(err) = set_value(&local_kv_tx, "vu_index", vu_index_value).await {
+        eprintln!("Setting the vu_index failed with error: {}", err);
+        return Ok(FunctionStatus::Failed);
+    }
+
+    // Without pacing the functions run once, otherwise the iterations repeat
+    // until the time is up, each starting at most once per interval. A failed
+    // iteration doesn't stop the virtual user, so that it keeps its pace when
+    // the server is slow, but it makes the virtual user fail in the end.
+    let mut iteration: i64 = 0;
+    loop {
+        let time_stamp = time_stamp();
+        let started_at = Instant::now();
+        let result = async {
+            set_value(&local_kv_tx, "iteration", Dynamic::from_int(iteration)).await?;
+            run_iteration(&functions, &global_kv_tx, &local_kv_tx, end_time).await
+        }
+        .await;
+        let status = match result {
+            Ok(status) => status,
+            Err(err) => {
+                eprintln!("Iteration #{} failed with error: {}", iteration + 1, err);
+                FunctionStatus::Failed
+            }
+        };
+        let iteration_time = started_at.elapsed();
+        let success = matches!(status, FunctionStatus::Passed);
+        if !success {
+            final_status = FunctionStatus::Failed;
+        }
+
+        let Some(pacing) = &pacing else { break };
+
+        let metric = IterationMetric {
+            vu_index,
+            iteration,
+            time_stamp,
+            elapsed_time: iteration_time.as_millis(),
+            success,
+        };
+        if let Err(err) = record_iteration(metric, &global_kv_tx).await {
+            eprintln!(
+                "Recording iteration #{} failed with error: {}",
+                iteration + 1,
+                err
+            );
+            final_status = FunctionStatus::Failed;
+        }
+
+        let interval = async {
+            let iteration_time = Dynamic::from_float(iteration_time.as_secs_f64());
+            set_value(&local_kv_tx, "iteration_time", iteration_time).await?;
+            pacing.interval(&local_kv_tx).await
+        }
+        .await;
+        let interval = match interval {
+            Ok(interval) => interval,
+            Err(err) => {
+                eprintln!("Pacing failed with error: {}", err);
+                final_status = FunctionStatus::Failed;
+                break;
+            }
+        };
+
+        // An iteration that took longer than the interval is followed right
+        // away by the next one.
+        let next_start = started_at + interval;
+        if next_start >= end_time {
+            break;
+        }
+        sleep_until(next_start).await;
+        iteration += 1;
+    }
+
+    drop(local_kv_tx);
+    if let Err(err) = local_kv_handle.await {
+        eprintln!("Local KV store task ended with error: {}", err);
+        final_status = FunctionStatus::Failed;
+    }
+
+    Ok(final_status)
+}
+
+async fn record_iteration(metric: IterationMetric, global_kv_tx: &Sender) -> Result<()> {
+    if should_collect_metrics(global_kv_tx).await? {
+        append_metric(global_kv_tx, metric).await?;
+    }
+    Ok(())
+}
+
+/// Runs the functions in order, until one of them fails or the time is up.
+async fn run_iteration(
+    functions: &[Function],
+    global_kv_tx: &Sender,
+    local_kv_tx: &Sender,
+    end_time: Instant,
+) -> FunctionResult {
+    let mut final_status = FunctionStatus::Passed;
+
     // Perform variable interpolation and execute the Functions.
-    for (index, function) in functions.into_iter().enumerate() {
+    for (index, function) in functions.iter().cloned().enumerate() {
         if Instant::now() >= end_time {
             break;
         }
@@ -85,21 +266,120 @@ pub async fn run_functions(
             let exec_local_kv = local_kv_tx.clone();
             let exec_global_kv = global_kv_tx.clone();
             async move {
-                // 1. Convert the Function to a string.
-                let function_str = serde_json::to_string(&function)?;
+                // 0. Interpolate the typed json values separately, as they would
+                // otherwise all end up as strings.
+                let mut function = function;
+                match &mut function {
+                    Function::HttpRequest(param) => {
+                        if let HttpBody::Json(value) = &mut param.body {
+                            interpolate_json_value(value, exec_local_kv.clone()).await?;
+                        }
+                    }
+                    Function::GraphQL(param) => {
+                        if let Some(value) = &mut param.variables {
+                            interpolate_json_value(value, exec_local_kv.clone()).await?;
+                        }
+                    }
+                    Function::Grpc(param) => {
+                        interpolate_json_value(&mut param.request, exec_local_kv.clone()).await?;
+                    }
+                    Function::Sql(param) => {
+                        for value in &mut param.params {
+                            interpolate_json_value(value, exec_local_kv.clone()).await?;
+                        }
+                    }
+                    _ => {}
+                }
+
+                // 1. Convert the Function to a string, leaving out the typed json
+                // values. They are already interpolated, and any `%|...|%` in
+                // their interpolated values must not be evaluated a second time.
+                let mut function_value = serde_json::to_value(&function)?;
+                let typed_values: Vec<_> = TYPED_JSON_VALUES
+                    .iter()
+                    .filter_map(|pointer| {
+                        let value = function_value.pointer_mut(pointer)?;
+                        Some((*pointer, std::mem::take(value)))
+                    })
+                    .collect();
+                let function_str = serde_json::to_string(&function_value)?;
 
                 // 2. Perform variable (string) interpolation and insert variable values.
                 let interpolated =
                     interpolate_variables(&function_str, exec_local_kv.clone()).await?;
 
                 // 3. Convert the interpolated string back to a Function that can be executed.
-                let executable_function: Function = serde_json::from_str(interpolated.as_ref())?;
+                let mut function_value: serde_json::Value =
+                    serde_json::from_str(interpolated.as_ref())?;
+                for (pointer, value) in typed_values {
+                    if let Some(slot) = function_value.pointer_mut(pointer) {
+                        *slot = value;
+                    }
+                }
+                let executable_function: Function = serde_json::from_value(function_value)?;
 
                 // 4. Execute the Function.
                 let remaining_time = end_time.checked_duration_since(Instant::now());
                 match executable_function {
                     Function::HttpRequest(param) => {
                         http_request::make_request(
+                            *param,
+                            remaining_time,
+                            exec_global_kv,
+                            exec_local_kv,
+                        )
+                        .await
+                    }
+                    Function::HttpSession(param) => {
+                        http_session::configure_session(param, exec_local_kv).await
+                    }
+                    Function::GraphQL(param) => {
+                        graphql::make_graphql_request(
+                            *param,
+                            remaining_time,
+                            exec_global_kv,
+                            exec_local_kv,
+                        )
+                        .await
+                    }
+                    Function::Grpc(param) => {
+                        grpc::make_grpc_request(
+                            *param,
+                            remaining_time,
+                            exec_global_kv,
+                            exec_local_kv,
+                        )
+                        .await
+                    }
+                    Function::WebSocketConnect(param) => {
+                        websocket::connect(param, remaining_time, exec_global_kv, exec_local_kv)
+                            .await
+                    }
+                    Function::WebSocketSend(param) => {
+                        websocket::send(param, remaining_time, exec_global_kv, exec_local_kv).await
+                    }
+                    Function::WebSocketReceive(param) => {
+                        websocket::receive(param, remaining_time, exec_global_kv, exec_local_kv)
+                            .await
+                    }
+                    Function::WebSocketClose(param) => {
+                        websocket::close(param, remaining_time, exec_global_kv, exec_local_kv).await
+                    }
+                    Function::TcpSend(param) => {
+                        let protocol = socket::Protocol::Tcp;
+                        socket::send(
+                            protocol,
+                            param,
+                            remaining_time,
+                            exec_global_kv,
+                            exec_local_kv,
+                        )
+                        .await
+                    }
+                    Function::UdpSend(param) => {
+                        let protocol = socket::Protocol::Udp;
+                        socket::send(
+                            protocol,
                             param,
                             remaining_time,
                             exec_global_kv,
@@ -107,8 +387,21 @@ pub async fn run_functions(
                         )
                         .await
                     }
+                    Function::Sql(param) => {
+                        sql::run_sql(param, remaining_time, exec_global_kv, exec_local_kv).await
+                    }
+                    Function::Redis(param) => {
+                        redis::send_command(param, remaining_time, exec_global_kv, exec_local_kv)
+                            .await
+                    }
+                    Function::Exec(param) => {
+                        exec::exec(param, remaining_time, exec_global_kv, exec_local_kv).await
+                    }
+                    Function::Rendezvous(param) => {
+                        rendezvous::rendezvous(param, remaining_time, exec_global_kv).await
+                    }
                     Function::Sleep(param) => {
-                        sleep::sleep(param, remaining_time, exec_global_kv).await
+                        sleep::sleep(param, remaining_time, exec_local_kv).await
                     }
                     Function::RunRhaiCode(param) => {
                         rhai_code::run_rhai_code(param, exec_global_kv, exec_local_kv).await
@@ -137,11 +430,5 @@ pub async fn run_functions(
         }
     }
 
-    drop(local_kv_tx);
-    if let Err(err) = local_kv_handle.await {
-        eprintln!("Local KV store task ended with error: {}", err);
-        final_status = FunctionStatus::Failed;
-    }
-
     Ok(final_status)
 }
@@ -1,23 +1,191 @@
 use std::time::Duration;
 
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use rand_distr::{Distribution as _, Exp, Normal, Poisson};
+use rhai::Dynamic;
 use serde::{Deserialize, Serialize};
 use tokio::time;
 
 use crate::kv_store::commands::Sender;
 
+use super::kv::{get_value, set_value};
 use super::result::*;
 
+/// A duration given either as a number of seconds, such as `1.5`, or as text
+/// with a unit, such as `"250ms"`, `"1.5s"`, `"2m"` or `"1h"`. Text without a
+/// unit is a number of seconds, so interpolated numbers work too.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum DurationValue {
+    Seconds(f64),
+    Text(String),
+}
+
+impl DurationValue {
+    pub fn to_duration(&self) -> Result<Duration> {
+        let seconds = match self {
+            DurationValue::Seconds(seconds) => *seconds,
+            DurationValue::Text(text) => {
+                let text = text.trim();
+                let split = text
+                    .find(|c: char| c.is_ascii_alphabetic())
+                    .unwrap_or(text.len());
+                let (value, unit) = text.split_at(split);
+                let value = value
+                    .trim()
+                    .parse::<f64>()
+                    .map_err(|_| format!("invalid duration: '{text}'"))?;
+                let scale = match unit {
+                    "ms" => 0.001,
+                    "" | "s" => 1.0,
+                    "m" => 60.0,
+                    "h" => 3600.0,
+                    _ => return Err(format!("invalid duration unit in '{text}'").into()),
+                };
+                value * scale
+            }
+        };
+        Ok(Duration::try_from_secs_f64(seconds)
+            .map_err(|_| format!("invalid duration: {seconds} seconds"))?)
+    }
+}
+
+fn to_seconds(value: &Option<DurationValue>) -> Result<Option<f64>> {
+    Ok(match value {
+        Some(value) => Some(value.to_duration()?.as_secs_f64()),
+        None => None,
+    })
+}
+
+/// Random think time. The drawn durations are kept within `min` and `max`,
+/// and are never negative.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum Distribution {
+    Uniform {
+        min: DurationValue,
+        max: DurationValue,
+    },
+
+    Normal {
+        mean: DurationValue,
+        std_dev: DurationValue,
+
+        #[serde(default)]
+        min: Option<DurationValue>,
+
+        #[serde(default)]
+        max: Option<DurationValue>,
+    },
+
+    Exponential {
+        mean: DurationValue,
+
+        #[serde(default)]
+        min: Option<DurationValue>,
+
+        #[serde(default)]
+        max: Option<DurationValue>,
+    },
+
+    /// Draws a whole number of milliseconds.
+    Poisson {
+        mean: DurationValue,
+
+        #[serde(default)]
+        min: Option<DurationValue>,
+
+        #[serde(default)]
+        max: Option<DurationValue>,
+    },
+}
+
+impl Distribution {
+    fn sample(&self, rng: &mut impl Rng) -> Result<Duration> {
+        let (seconds, min, max) = match self {
+            Distribution::Uniform { min, max } => {
+                let min = min.to_duration()?.as_secs_f64();
+                let max = max.to_duration()?.as_secs_f64();
+                if min > max {
+                    return Err("the min of the distribution is greater than its max".into());
+                }
+                (rng.gen_range(min..=max), None, None)
+            }
+            Distribution::Normal {
+                mean,
+                std_dev,
+                min,
+                max,
+            } => {
+                let mean = mean.to_duration()?.as_secs_f64();
+                let std_dev = std_dev.to_duration()?.as_secs_f64();
+                let seconds = Normal::new(mean, std_dev)?.sample(rng);
+                (seconds, to_seconds(min)?, to_seconds(max)?)
+            }
+            Distribution::Exponential { mean, min, max } => {
+                let mean = mean.to_duration()?.as_secs_f64();
+                let seconds = Exp::new(1.0 / mean)?.sample(rng);
+                (seconds, to_seconds(min)?, to_seconds(max)?)
+            }
+            Distribution::Poisson { mean, min, max } => {
+                let mean = mean.to_duration()?.as_secs_f64() * 1000.0;
+                let millis: f64 = Poisson::new(mean)?.sample(rng);
+                (millis / 1000.0, to_seconds(min)?, to_seconds(max)?)
+            }
+        };
+
+        let seconds = seconds
+            .max(min.unwrap_or(0.0))
+            .min(max.unwrap_or(f64::MAX))
+            .max(0.0);
+        Ok(Duration::from_secs_f64(seconds))
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct SleepParam {
-    duration: String,
+    /// Fixed time to sleep. Either this or a `distribution` is needed.
+    #[serde(default)]
+    pub duration: Option<DurationValue>,
+
+    #[serde(default)]
+    pub distribution: Option<Distribution>,
+
+    /// Makes the drawn durations reproducible. Each virtual user draws its own
+    /// sequence, seeded with `seed` plus the index of the virtual user.
+    #[serde(default)]
+    pub seed: Option<u64>,
 }
 
-pub async fn sleep(param: SleepParam, timeout: Option<Duration>, _kv_tx: Sender) -> FunctionResult {
-    println!("Sleeping for {} secs", param.duration);
-    let duration = param.duration.parse::<u64>()?;
+/// Draws from the generator of the virtual user for the seed, which is seeded
+/// on first use and kept in the local store so that each sleep with the same
+/// seed continues the sequence.
+async fn sample_seeded(distribution: &Distribution, seed: u64, kv_tx: &Sender) -> Result<Duration> {
+    let key = format!("sleep_rng:{seed}");
+    let mut rng = match get_value(kv_tx, &key).await?.try_cast::<StdRng>() {
+        Some(rng) => rng,
+        None => {
+            let vu_index = get_value(kv_tx, "vu_index").await?.as_int().unwrap_or(0);
+            StdRng::seed_from_u64(seed.wrapping_add(vu_index as u64))
+        }
+    };
+    let duration = distribution.sample(&mut rng)?;
+    set_value(kv_tx, &key, Dynamic::from(rng)).await?;
+    Ok(duration)
+}
+
+pub async fn sleep(param: SleepParam, timeout: Option<Duration>, kv_tx: Sender) -> FunctionResult {
+    let duration = match (&param.duration, &param.distribution, param.seed) {
+        (Some(duration), None, _) => duration.to_duration()?,
+        (None, Some(distribution), Some(seed)) => sample_seeded(distribution, seed, &kv_tx).await?,
+        (None, Some(distribution), None) => distribution.sample(&mut rand::thread_rng())?,
+        _ => return Err("sleep needs either a duration or a distribution".into()),
+    };
+
+    println!("Sleeping for {:?}", duration);
     let sleep_time = match timeout {
-        Some(t) => std::cmp::min(Duration::from_secs(duration), t),
-        None => Duration::from_secs(duration),
+        Some(t) => std::cmp::min(duration, t),
+        None => duration,
     };
     time::sleep(sleep_time).await;
 
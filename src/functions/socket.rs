@@ -0,0 +1,340 @@
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use base64::Engine;
+use rhai::Dynamic;
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpStream, UdpSocket};
+use tokio::time::Instant;
+
+use crate::kv_store::commands::Sender;
+
+use super::kv::set_value;
+use super::metrics::{append_metric, should_collect_metrics, time_stamp};
+use super::result::*;
+use super::timeout::function_timeout;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SocketMetric {
+    /// Either `tcp` or `udp`.
+    pub protocol: String,
+
+    pub address: String,
+
+    /// When did the connection start
+    pub time_stamp: String,
+
+    /// Time taken to establish the connection. Only set for TCP.
+    pub connect_time: Option<u128>,
+
+    /// Time from when the payload was sent until the reply was complete. Not
+    /// set when no reply is read.
+    pub round_trip_time: Option<u128>,
+
+    /// Total time of the function, from connecting until the reply was read.
+    pub elapsed_time: u128,
+
+    pub bytes_sent: usize,
+    pub bytes_received: usize,
+
+    pub success: bool,
+
+    /// Why the function failed, if it did.
+    pub error: String,
+
+    pub tag: String,
+}
+
+/// Bytes that are written as text or decoded from hex or base64.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum Payload {
+    Text(String),
+    Hex(String),
+    Base64(String),
+}
+
+impl Payload {
+    fn to_bytes(&self) -> Result<Vec<u8>> {
+        let bytes = match self {
+            Payload::Text(text) => text.clone().into_bytes(),
+            Payload::Hex(data) => hex::decode(data.replace(char::is_whitespace, ""))?,
+            Payload::Base64(data) => base64::engine::general_purpose::STANDARD.decode(data)?,
+        };
+        Ok(bytes)
+    }
+}
+
+/// How the reply is stored in the `tcp_response` and `udp_response` variables.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy)]
+pub enum ReplyEncoding {
+    /// The reply decoded as UTF-8, replacing any invalid sequence.
+    #[default]
+    Text,
+
+    Hex,
+    Base64,
+}
+
+/// Determines when the reply is complete. For UDP, whole datagrams are read
+/// until it is.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum ReadUntil {
+    /// Until the text is received, which is kept at the end of the reply. It
+    /// can't be empty.
+    Delimiter(String),
+
+    /// Until the number of bytes is received.
+    Bytes(usize),
+
+    /// Everything that is received until the timeout, or until the server
+    /// closes the connection.
+    Timeout,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SocketSendParam {
+    /// Address of the server as `host:port`.
+    pub address: String,
+
+    pub payload: Payload,
+
+    /// No reply is read when not set.
+    #[serde(default)]
+    pub read_until: Option<ReadUntil>,
+
+    #[serde(default)]
+    pub reply_encoding: ReplyEncoding,
+
+    #[serde(default)]
+    pub timeout: Option<u64>,
+
+    #[serde(default)]
+    pub tag: Option<String>,
+}
+
+/// Timing of the function and the reply, which are recorded in the metrics.
+#[derive(Default)]
+struct Outcome {
+    connect_time: Option<Duration>,
+    round_trip_time: Option<Duration>,
+    bytes_sent: usize,
+    reply: Vec<u8>,
+    error: Option<String>,
+}
+
+/// Returns the length of the reply if it's complete. `searched_len` is the
+/// length of the reply when it was last checked, so that the delimiter is only
+/// looked for in the bytes that could complete it.
+fn complete_len(read_until: &ReadUntil, reply: &[u8], searched_len: usize) -> Option<usize> {
+    match read_until {
+        ReadUntil::Delimiter(delimiter) => {
+            let delimiter = delimiter.as_bytes();
+            let start = searched_len.saturating_sub(delimiter.len().saturating_sub(1));
+            reply[start..]
+                .windows(delimiter.len())
+                .position(|window| window == delimiter)
+                .map(|position| start + position + delimiter.len())
+        }
+        ReadUntil::Bytes(count) => (reply.len() >= *count).then_some(*count),
+        ReadUntil::Timeout => None,
+    }
+}
+
+/// A connected socket that the reply is read from.
+enum Socket<'a> {
+    Tcp(&'a mut TcpStream),
+    Udp(&'a UdpSocket),
+}
+
+impl Socket<'_> {
+    /// Reads the next bytes, or the next datagram for UDP. Returns 0 when the
+    /// connection was closed.
+    async fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            Socket::Tcp(stream) => stream.read(buf).await,
+            Socket::Udp(socket) => socket.recv(buf).await,
+        }
+    }
+}
+
+/// Reads from the socket until the reply is complete. The reply is cut right
+/// after the delimiter or the byte count.
+async fn read_reply(
+    socket: &mut Socket<'_>,
+    read_until: &ReadUntil,
+    deadline: Instant,
+) -> std::result::Result<Vec<u8>, String> {
+    let is_timeout = matches!(read_until, ReadUntil::Timeout);
+    let mut reply = Vec::new();
+    let mut searched_len = 0;
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+        if let Some(len) = complete_len(read_until, &reply, searched_len) {
+            reply.truncate(len);
+            return Ok(reply);
+        }
+        searched_len = reply.len();
+
+        match tokio::time::timeout_at(deadline, socket.read(&mut buf)).await {
+            Ok(Ok(0)) if is_timeout => return Ok(reply),
+            Ok(Ok(0)) => {
+                return Err("the connection was closed before the reply was complete".into())
+            }
+            Ok(Ok(len)) => reply.extend_from_slice(&buf[..len]),
+            Ok(Err(err)) => return Err(format!("Read failed: {err}")),
+            Err(_) if is_timeout => return Ok(reply),
+            Err(_) => return Err("timed out waiting for the reply".into()),
+        }
+    }
+}
+
+async fn tcp_send(
+    param: &SocketSendParam,
+    payload: &[u8],
+    deadline: Instant,
+    outcome: &mut Outcome,
+) -> std::result::Result<(), String> {
+    let started_at = Instant::now();
+    let mut stream = tokio::time::timeout_at(deadline, TcpStream::connect(&param.address))
+        .await
+        .map_err(|_| "Connection timed out".to_string())?
+        .map_err(|err| format!("Connection failed: {err}"))?;
+    outcome.connect_time = Some(started_at.elapsed());
+
+    let sent_at = Instant::now();
+    tokio::time::timeout_at(deadline, stream.write_all(payload))
+        .await
+        .map_err(|_| "Send timed out".to_string())?
+        .map_err(|err| format!("Send failed: {err}"))?;
+    outcome.bytes_sent = payload.len();
+
+    if let Some(read_until) = &param.read_until {
+        outcome.reply = read_reply(&mut Socket::Tcp(&mut stream), read_until, deadline).await?;
+        outcome.round_trip_time = Some(sent_at.elapsed());
+    }
+
+    Ok(())
+}
+
+async fn udp_send(
+    param: &SocketSendParam,
+    payload: &[u8],
+    deadline: Instant,
+    outcome: &mut Outcome,
+) -> std::result::Result<(), String> {
+    let connect = async {
+        let address = tokio::net::lookup_host(&param.address)
+            .await
+            .map_err(|err| format!("Failed to resolve {}: {err}", param.address))?
+            .next()
+            .ok_or_else(|| format!("Failed to resolve {}", param.address))?;
+        let local_address: SocketAddr = match address {
+            SocketAddr::V4(_) => ([0, 0, 0, 0], 0).into(),
+            SocketAddr::V6(_) => ([0u16; 8], 0).into(),
+        };
+
+        let socket = UdpSocket::bind(local_address)
+            .await
+            .map_err(|err| format!("Bind failed: {err}"))?;
+        socket
+            .connect(address)
+            .await
+            .map_err(|err| format!("Connection failed: {err}"))?;
+        Ok::<_, String>(socket)
+    };
+    let socket = tokio::time::timeout_at(deadline, connect)
+        .await
+        .map_err(|_| "Connection timed out".to_string())??;
+
+    let sent_at = Instant::now();
+    outcome.bytes_sent = socket
+        .send(payload)
+        .await
+        .map_err(|err| format!("Send failed: {err}"))?;
+
+    if let Some(read_until) = &param.read_until {
+        outcome.reply = read_reply(&mut Socket::Udp(&socket), read_until, deadline).await?;
+        outcome.round_trip_time = Some(sent_at.elapsed());
+    }
+
+    Ok(())
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Protocol {
+    Tcp,
+    Udp,
+}
+
+impl Protocol {
+    fn as_str(self) -> &'static str {
+        match self {
+            Protocol::Tcp => "tcp",
+            Protocol::Udp => "udp",
+        }
+    }
+}
+
+/// Sends the payload over TCP or UDP and stores the reply, if one is read, in
+/// the `tcp_response` or `udp_response` variable.
+pub async fn send(
+    protocol: Protocol,
+    param: SocketSendParam,
+    timeout: Option<Duration>,
+    global_kv_tx: Sender,
+    local_kv_tx: Sender,
+) -> FunctionResult {
+    if let Some(ReadUntil::Delimiter(delimiter)) = &param.read_until {
+        if delimiter.is_empty() {
+            return Err("the Delimiter of read_until can't be empty".into());
+        }
+    }
+    let payload = param.payload.to_bytes()?;
+    let deadline = Instant::now() + function_timeout(param.timeout, timeout);
+
+    let time_stamp = time_stamp();
+    let started_at = Instant::now();
+    let mut outcome = Outcome::default();
+    let result = match protocol {
+        Protocol::Tcp => tcp_send(&param, &payload, deadline, &mut outcome).await,
+        Protocol::Udp => udp_send(&param, &payload, deadline, &mut outcome).await,
+    };
+    outcome.error = result.err();
+    let elapsed_time = started_at.elapsed();
+
+    if param.read_until.is_some() {
+        let reply = match (&outcome.error, param.reply_encoding) {
+            (Some(error), _) => error.clone(),
+            (None, ReplyEncoding::Text) => String::from_utf8_lossy(&outcome.reply).into_owned(),
+            (None, ReplyEncoding::Hex) => hex::encode(&outcome.reply),
+            (None, ReplyEncoding::Base64) => {
+                base64::engine::general_purpose::STANDARD.encode(&outcome.reply)
+            }
+        };
+        let key = format!("{}_response", protocol.as_str());
+        set_value(&local_kv_tx, &key, Dynamic::from(reply)).await?;
+    }
+
+    if should_collect_metrics(&global_kv_tx).await? {
+        let metric = SocketMetric {
+            protocol: protocol.as_str().into(),
+            address: param.address,
+            time_stamp,
+            connect_time: outcome.connect_time.map(|time| time.as_millis()),
+            round_trip_time: outcome.round_trip_time.map(|time| time.as_millis()),
+            elapsed_time: elapsed_time.as_millis(),
+            bytes_sent: outcome.bytes_sent,
+            bytes_received: outcome.reply.len(),
+            success: outcome.error.is_none(),
+            error: outcome.error.clone().unwrap_or_default(),
+            tag: param.tag.unwrap_or_default(),
+        };
+        append_metric(&global_kv_tx, metric).await?;
+    }
+
+    match outcome.error {
+        Some(_) => Ok(FunctionStatus::Failed),
+        None => Ok(FunctionStatus::Passed),
+    }
+}
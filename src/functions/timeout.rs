@@ -0,0 +1,14 @@
+use std::time::Duration;
+
+/// Timeout of a function when its parameters don't set one (in seconds).
+const DEFAULT_TIMEOUT: u64 = 60;
+
+/// Returns the timeout of a function: the `timeout` of its parameters, or the
+/// default one, cut short to the time that's left for the virtual user.
+pub fn function_timeout(param_timeout: Option<u64>, remaining_time: Option<Duration>) -> Duration {
+    let param_timeout = Duration::from_secs(param_timeout.unwrap_or(DEFAULT_TIMEOUT));
+    match remaining_time {
+        Some(remaining_time) => std::cmp::min(param_timeout, remaining_time),
+        None => param_timeout,
+    }
+}
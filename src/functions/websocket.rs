@@ -0,0 +1,516 @@
+use std::collections::BTreeMap;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use base64::Engine;
+use futures::{SinkExt, StreamExt};
+use regex::Regex;
+use rhai::Dynamic;
+use serde::{Deserialize, Serialize};
+use tokio::net::TcpStream;
+use tokio::sync::Mutex;
+use tokio::time::Instant;
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::handshake::client::Request;
+use tokio_tungstenite::tungstenite::http::{HeaderName, HeaderValue};
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+
+use crate::kv_store::commands::Sender;
+
+use super::http_request::{get_http_config, resolve_address, KeyValue, TlsConfig};
+use super::kv::{delete_value, get_value, named_key, set_value};
+use super::metrics::{append_metric, should_collect_metrics, time_stamp};
+use super::result::*;
+use super::timeout::function_timeout;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct WebSocketMetric {
+    pub url: String,
+
+    /// One of `connect`, `send`, `receive` or `close`.
+    pub operation: String,
+
+    /// Name of the connection, empty for the default one.
+    pub connection: String,
+
+    /// When did the operation start
+    pub time_stamp: String,
+
+    /// Time taken by the operation, which is the opening handshake for
+    /// `connect` and the wait for the matching message for `receive`.
+    pub elapsed_time: u128,
+
+    /// Time from the last message sent on the connection until the matching
+    /// message was received. Only set for `receive`.
+    pub round_trip_time: Option<u128>,
+
+    /// Size of the message that was sent or received (in bytes)
+    pub message_size: usize,
+
+    /// Number of messages sent and received on the connection so far.
+    pub frames_sent: u64,
+    pub frames_received: u64,
+
+    pub success: bool,
+
+    /// Why the operation failed, if it did.
+    pub error: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct WebSocketConnectParam {
+    /// Name of the connection, the default connection is used when not set.
+    #[serde(default)]
+    pub name: Option<String>,
+
+    pub url: String,
+
+    #[serde(default)]
+    pub headers: Vec<KeyValue<String>>,
+
+    #[serde(default)]
+    pub timeout: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum WebSocketMessage {
+    Text(String),
+
+    /// Base64 encoded bytes, sent as a binary message.
+    Binary(String),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct WebSocketSendParam {
+    #[serde(default)]
+    pub name: Option<String>,
+
+    pub message: WebSocketMessage,
+
+    #[serde(default)]
+    pub timeout: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct WebSocketReceiveParam {
+    #[serde(default)]
+    pub name: Option<String>,
+
+    /// Regex that the message has to match, any other message is skipped.
+    /// The first message is taken when not set.
+    #[serde(default)]
+    pub pattern: Option<String>,
+
+    #[serde(default)]
+    pub timeout: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct WebSocketCloseParam {
+    #[serde(default)]
+    pub name: Option<String>,
+}
+
+struct Connection {
+    stream: WebSocketStream<MaybeTlsStream<TcpStream>>,
+    url: String,
+    frames_sent: u64,
+    frames_received: u64,
+    last_sent_at: Option<Instant>,
+}
+
+/// An open connection of a virtual user, stored in its kv store.
+#[derive(Clone)]
+struct SharedConnection(Arc<Mutex<Connection>>);
+
+/// Outcome of an operation, which is recorded in the metrics.
+struct Outcome {
+    elapsed_time: Duration,
+    round_trip_time: Option<Duration>,
+    message_size: usize,
+    error: Option<String>,
+}
+
+/// Reads the client certificate like the http client does: PKCS#12 files are
+/// detected by their extension, anything else is PEM with the key in
+/// `client_key` or in the certificate file itself.
+async fn client_identity(tls: &TlsConfig, path: &str) -> Result<native_tls::Identity> {
+    let lowercase_path = path.to_lowercase();
+    if lowercase_path.ends_with(".p12") || lowercase_path.ends_with(".pfx") {
+        let der = tokio::fs::read(path).await?;
+        let password = tls.client_key_password.as_deref().unwrap_or_default();
+        return Ok(native_tls::Identity::from_pkcs12(&der, password)?);
+    }
+
+    if tls.client_key_password.is_some() {
+        return Err(
+            "websocket connections can't use an encrypted PEM key, use a PKCS#12 file".into(),
+        );
+    }
+    let cert = tokio::fs::read(path).await?;
+    let key = match &tls.client_key {
+        Some(key) => tokio::fs::read(key).await?,
+        None => cert.clone(),
+    };
+    Ok(native_tls::Identity::from_pkcs8(&cert, &key)?)
+}
+
+async fn tls_connector(tls: &TlsConfig) -> Result<native_tls::TlsConnector> {
+    let mut builder = native_tls::TlsConnector::builder();
+    if !tls.verify {
+        builder
+            .danger_accept_invalid_certs(true)
+            .danger_accept_invalid_hostnames(true);
+    }
+    if let Some(ca_file) = &tls.ca_file {
+        let pem = tokio::fs::read(ca_file).await?;
+        builder.add_root_certificate(native_tls::Certificate::from_pem(&pem)?);
+    }
+    if let Some(client_cert) = &tls.client_cert {
+        builder.identity(client_identity(tls, client_cert).await?);
+    }
+
+    Ok(builder.build()?)
+}
+
+/// Opens the connection to the host of the url, which is looked up in the
+/// `resolve` map first. The TLS handshake of a `wss://` url is made here, with
+/// the `sni` name when one is set.
+async fn open_stream(
+    request: Request,
+    tls: Option<&TlsConfig>,
+    resolve: &BTreeMap<String, String>,
+) -> Result<WebSocketStream<MaybeTlsStream<TcpStream>>> {
+    let secure = match request.uri().scheme_str() {
+        Some("wss") => true,
+        Some("ws") => false,
+        _ => return Err("the url must start with ws:// or wss://".into()),
+    };
+    let host = request.uri().host().ok_or("url has no host")?;
+    let host = host
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .to_string();
+    let port = request
+        .uri()
+        .port_u16()
+        .unwrap_or(if secure { 443 } else { 80 });
+
+    let address = resolve_address(&host, port, resolve).await?;
+    let tcp = TcpStream::connect(address).await?;
+    if !secure {
+        let (stream, _response) =
+            tokio_tungstenite::client_async(request, MaybeTlsStream::Plain(tcp)).await?;
+        return Ok(stream);
+    }
+
+    let connector = match tls {
+        Some(tls) => tls_connector(tls).await?,
+        None => native_tls::TlsConnector::new()?,
+    };
+    let server_name = tls.and_then(|tls| tls.sni.as_deref()).unwrap_or(&host);
+    let tls_stream = tokio_native_tls::TlsConnector::from(connector)
+        .connect(server_name, tcp)
+        .await?;
+    let (stream, _response) =
+        tokio_tungstenite::client_async(request, MaybeTlsStream::NativeTls(tls_stream)).await?;
+    Ok(stream)
+}
+
+async fn get_connection(name: Option<&str>, local_kv_tx: &Sender) -> Result<SharedConnection> {
+    let key = named_key("websocket", name);
+    get_value(local_kv_tx, &key)
+        .await?
+        .try_cast::<SharedConnection>()
+        .ok_or_else(|| {
+            format!(
+                "websocket connection '{}' is not open",
+                name.unwrap_or_default()
+            )
+            .into()
+        })
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn record_metric(
+    url: String,
+    operation: &str,
+    name: Option<&str>,
+    time_stamp: String,
+    outcome: &Outcome,
+    frames_sent: u64,
+    frames_received: u64,
+    global_kv_tx: &Sender,
+) -> Result<()> {
+    if !should_collect_metrics(global_kv_tx).await? {
+        return Ok(());
+    }
+
+    let metric = WebSocketMetric {
+        url,
+        operation: operation.into(),
+        connection: name.unwrap_or_default().into(),
+        time_stamp,
+        elapsed_time: outcome.elapsed_time.as_millis(),
+        round_trip_time: outcome.round_trip_time.map(|time| time.as_millis()),
+        message_size: outcome.message_size,
+        frames_sent,
+        frames_received,
+        success: outcome.error.is_none(),
+        error: outcome.error.clone().unwrap_or_default(),
+    };
+    append_metric(global_kv_tx, metric).await
+}
+
+pub async fn connect(
+    param: WebSocketConnectParam,
+    timeout: Option<Duration>,
+    global_kv_tx: Sender,
+    local_kv_tx: Sender,
+) -> FunctionResult {
+    let timeout = function_timeout(param.timeout, timeout);
+    let mut request = param.url.as_str().into_client_request()?;
+    for KeyValue(key, value) in &param.headers {
+        request
+            .headers_mut()
+            .insert(HeaderName::from_str(key)?, HeaderValue::from_str(value)?);
+    }
+
+    // The TLS and resolve settings are shared with the http requests of the
+    // flow.
+    let http_config = get_http_config(&global_kv_tx).await?;
+
+    let time_stamp = time_stamp();
+    let started_at = Instant::now();
+    let result = tokio::time::timeout(
+        timeout,
+        open_stream(request, http_config.tls.as_ref(), &http_config.resolve),
+    )
+    .await;
+    let elapsed_time = started_at.elapsed();
+
+    let error = match result {
+        Ok(Ok(stream)) => {
+            let connection = SharedConnection(Arc::new(Mutex::new(Connection {
+                stream,
+                url: param.url.clone(),
+                frames_sent: 0,
+                frames_received: 0,
+                last_sent_at: None,
+            })));
+            let key = named_key("websocket", param.name.as_deref());
+            set_value(&local_kv_tx, &key, Dynamic::from(connection)).await?;
+            None
+        }
+        Ok(Err(err)) => Some(format!("Connection failed: {err}")),
+        Err(_) => Some("Connection timed out".to_string()),
+    };
+
+    let outcome = Outcome {
+        elapsed_time,
+        round_trip_time: None,
+        message_size: 0,
+        error,
+    };
+    record_metric(
+        param.url,
+        "connect",
+        param.name.as_deref(),
+        time_stamp,
+        &outcome,
+        0,
+        0,
+        &global_kv_tx,
+    )
+    .await?;
+
+    match outcome.error {
+        Some(_) => Ok(FunctionStatus::Failed),
+        None => Ok(FunctionStatus::Passed),
+    }
+}
+
+pub async fn send(
+    param: WebSocketSendParam,
+    timeout: Option<Duration>,
+    global_kv_tx: Sender,
+    local_kv_tx: Sender,
+) -> FunctionResult {
+    let timeout = function_timeout(param.timeout, timeout);
+    let connection = get_connection(param.name.as_deref(), &local_kv_tx).await?;
+    let mut connection = connection.0.lock().await;
+
+    let message = match param.message {
+        WebSocketMessage::Text(text) => Message::Text(text),
+        WebSocketMessage::Binary(data) => {
+            Message::Binary(base64::engine::general_purpose::STANDARD.decode(data)?)
+        }
+    };
+    let message_size = message.len();
+
+    let time_stamp = time_stamp();
+    let started_at = Instant::now();
+    let error = match tokio::time::timeout(timeout, connection.stream.send(message)).await {
+        Ok(Ok(())) => {
+            connection.frames_sent += 1;
+            connection.last_sent_at = Some(started_at);
+            None
+        }
+        Ok(Err(err)) => Some(format!("Send failed: {err}")),
+        Err(_) => Some("Send timed out".to_string()),
+    };
+
+    let outcome = Outcome {
+        elapsed_time: started_at.elapsed(),
+        round_trip_time: None,
+        message_size,
+        error,
+    };
+    record_metric(
+        connection.url.clone(),
+        "send",
+        param.name.as_deref(),
+        time_stamp,
+        &outcome,
+        connection.frames_sent,
+        connection.frames_received,
+        &global_kv_tx,
+    )
+    .await?;
+
+    match outcome.error {
+        Some(_) => Ok(FunctionStatus::Failed),
+        None => Ok(FunctionStatus::Passed),
+    }
+}
+
+/// Waits for a message that matches the pattern and stores it in the
+/// `websocket_message` variable. Text messages are stored as strings, binary
+/// ones as blobs.
+pub async fn receive(
+    param: WebSocketReceiveParam,
+    timeout: Option<Duration>,
+    global_kv_tx: Sender,
+    local_kv_tx: Sender,
+) -> FunctionResult {
+    let timeout = function_timeout(param.timeout, timeout);
+    let pattern = match &param.pattern {
+        Some(pattern) => Some(Regex::new(pattern)?),
+        None => None,
+    };
+    let connection = get_connection(param.name.as_deref(), &local_kv_tx).await?;
+    let mut connection = connection.0.lock().await;
+
+    let time_stamp = time_stamp();
+    let started_at = Instant::now();
+    let deadline = started_at + timeout;
+    let (message, error) = loop {
+        let message = match tokio::time::timeout_at(deadline, connection.stream.next()).await {
+            Ok(Some(Ok(message))) => message,
+            Ok(Some(Err(err))) => break (None, Some(format!("Receive failed: {err}"))),
+            Ok(None) => break (None, Some("Connection closed".to_string())),
+            Err(_) => break (None, Some("Timed out waiting for a message".to_string())),
+        };
+
+        let text = match &message {
+            Message::Text(text) => text.clone(),
+            Message::Binary(data) => String::from_utf8_lossy(data).into_owned(),
+            Message::Close(_) => break (None, Some("Connection closed by the server".into())),
+            // Pings are answered by the stream itself.
+            Message::Ping(_) | Message::Pong(_) | Message::Frame(_) => continue,
+        };
+        connection.frames_received += 1;
+
+        if pattern
+            .as_ref()
+            .is_none_or(|pattern| pattern.is_match(&text))
+        {
+            break (Some(message), None);
+        }
+    };
+
+    let message_size = message.as_ref().map_or(0, |message| message.len());
+    let round_trip_time = match (&message, connection.last_sent_at) {
+        (Some(_), Some(last_sent_at)) => Some(last_sent_at.elapsed()),
+        _ => None,
+    };
+    let outcome = Outcome {
+        elapsed_time: started_at.elapsed(),
+        round_trip_time,
+        message_size,
+        error,
+    };
+
+    match message {
+        Some(Message::Text(text)) => {
+            set_value(&local_kv_tx, "websocket_message", Dynamic::from(text)).await?
+        }
+        Some(Message::Binary(data)) => {
+            set_value(&local_kv_tx, "websocket_message", Dynamic::from_blob(data)).await?
+        }
+        _ => {}
+    }
+
+    record_metric(
+        connection.url.clone(),
+        "receive",
+        param.name.as_deref(),
+        time_stamp,
+        &outcome,
+        connection.frames_sent,
+        connection.frames_received,
+        &global_kv_tx,
+    )
+    .await?;
+
+    match outcome.error {
+        Some(_) => Ok(FunctionStatus::Failed),
+        None => Ok(FunctionStatus::Passed),
+    }
+}
+
+pub async fn close(
+    param: WebSocketCloseParam,
+    timeout: Option<Duration>,
+    global_kv_tx: Sender,
+    local_kv_tx: Sender,
+) -> FunctionResult {
+    let timeout = function_timeout(None, timeout);
+    let key = named_key("websocket", param.name.as_deref());
+    let connection = get_connection(param.name.as_deref(), &local_kv_tx).await?;
+    delete_value(&local_kv_tx, &key).await?;
+    let mut connection = connection.0.lock().await;
+
+    let time_stamp = time_stamp();
+    let started_at = Instant::now();
+    let error = match tokio::time::timeout(timeout, connection.stream.close(None)).await {
+        Ok(Ok(())) => None,
+        Ok(Err(err)) => Some(format!("Close failed: {err}")),
+        Err(_) => Some("Close timed out".to_string()),
+    };
+
+    let outcome = Outcome {
+        elapsed_time: started_at.elapsed(),
+        round_trip_time: None,
+        message_size: 0,
+        error,
+    };
+    record_metric(
+        connection.url.clone(),
+        "close",
+        param.name.as_deref(),
+        time_stamp,
+        &outcome,
+        connection.frames_sent,
+        connection.frames_received,
+        &global_kv_tx,
+    )
+    .await?;
+
+    match outcome.error {
+        Some(_) => Ok(FunctionStatus::Failed),
+        None => Ok(FunctionStatus::Passed),
+    }
+}
@@ -31,6 +31,13 @@ pub enum Command {
         value: Dynamic,
         resp: Responder<()>,
     },
+    /// Returns the value of the key, setting it to `value` first if the key
+    /// doesn't exist.
+    GetOrSet {
+        key: String,
+        value: Dynamic,
+        resp: Responder<Value>,
+    },
     SetArray {
         key: String,
         value: Array,
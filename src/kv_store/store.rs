@@ -105,6 +105,16 @@ pub async fn new() -> (JoinHandle<()>, Sender) {
                     let exists = store.exists(key);
                     let _ = resp.send(Ok(exists));
                 }
+                Command::GetOrSet { key, value, resp } => {
+                    let value = match store.get(&key) {
+                        Some(val) => val.clone(),
+                        None => {
+                            store.set(key, Value::Dynamic(value.clone()));
+                            Value::Dynamic(value)
+                        }
+                    };
+                    let _ = resp.send(Ok(value));
+                }
                 Command::SetArray { key, value, resp } => {
                     store.set(key, Value::Array(value));
                     let _ = resp.send(empty_ok);